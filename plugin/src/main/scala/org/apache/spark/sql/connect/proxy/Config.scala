@@ -1,24 +1,49 @@
 package org.apache.spark.sql.connect.proxy
 
+import java.io.File
+import java.nio.charset.StandardCharsets
+import java.nio.file.Files
+
+import org.apache.spark.SparkConf
+import org.apache.spark.SparkFiles
 import org.apache.spark.internal.config.ConfigBuilder
-import java.util.concurrent.TimeUnit
 
 object Config {
-  // The auth token that must be used by the client to connect
+  // Environment variable the proxy can pass the auth token in
+  val SPARK_CONNECT_PROXY_TOKEN_ENV = "SPARK_CONNECT_PROXY_TOKEN"
+
+  // File the proxy can write the auth token to. In cluster deploy mode the file is
+  // shipped with the driver and only its name is given
+  val SPARK_CONNECT_PROXY_TOKEN_FILE =
+    ConfigBuilder("spark.connect.proxy.tokenFile")
+      .stringConf
+      .createOptional
+
+  // The auth token itself, as passed by older versions of the proxy
   val SPARK_CONNECT_PROXY_TOKEN =
     ConfigBuilder("spark.connect.proxy.token")
       .stringConf
       .createOptional
 
-  // The auth token that must be used by the client to connect
+  // The address of the proxy to send the connect service address to
   val SPARK_CONNECT_PROXY_CALLBACK =
     ConfigBuilder("spark.connect.proxy.callback")
       .stringConf
       .createOptional
 
-  // How long after no activity do we kill the session
-  val SPARK_CONNECT_PROXY_IDLE_TIMEOUT =
-    ConfigBuilder("spark.connect.proxy.idle.timeout")
-      .timeConf(TimeUnit.SECONDS)
-      .createOptional
+  // The auth token that must be used by the client to connect
+  def token(conf: SparkConf): String = {
+    val token = sys.env.get(SPARK_CONNECT_PROXY_TOKEN_ENV)
+      .orElse(conf.get(SPARK_CONNECT_PROXY_TOKEN_FILE).map(readToken))
+      .orElse(conf.get(SPARK_CONNECT_PROXY_TOKEN))
+    assert(token.nonEmpty, "No token provided, can't authenticate requests")
+    token.get
+  }
+
+  private def readToken(path: String): String = {
+    val file = new File(path)
+    // Files shipped with the driver are looked up by name
+    val resolved = if (file.isAbsolute) file else new File(SparkFiles.get(file.getName))
+    new String(Files.readAllBytes(resolved.toPath), StandardCharsets.UTF_8).trim
+  }
 }
@@ -10,11 +10,7 @@ class SparkConnectProxyInterceptor extends ServerInterceptor {
 
   val sparkContext = SparkContext.getActive.get
 
-  val token = {
-    val token = sparkContext.getConf.get(Config.SPARK_CONNECT_PROXY_TOKEN)
-    assert(token.nonEmpty, "No token provided, can't authenticate requests")
-    token.get
-  }
+  val token = Config.token(sparkContext.getConf)
 
   override def interceptCall[ReqT, RespT](
       call: ServerCall[ReqT,RespT],
@@ -1,6 +1,7 @@
 package org.apache.spark.sql.connect.proxy
 
 import java.net.http.HttpRequest
+import java.net.InetAddress
 import java.net.URI
 import java.time.Duration
 
@@ -25,16 +26,19 @@ class SparkConnectProxyListener(conf: SparkConf) extends SparkListener with Logg
     addr.get
   }
 
-  val token = {
-    val token = conf.get(Config.SPARK_CONNECT_PROXY_TOKEN)
-    assert(token.nonEmpty, "No token provided, can't authenticate requests")
-    token.get
-  }
+  val token = Config.token(conf)
 
   override def onOtherEvent(event: SparkListenerEvent): Unit = {
     event match {
       case SparkListenerConnectServiceStarted(hostAddress, bindingPort, _, _) =>
-        val connectUri = s"$hostAddress:$bindingPort"
+        // A wildcard address can't be reached from the proxy when the driver runs on a
+        // cluster, so fall back to the driver host, which is the driver service in k8s
+        val host = if (InetAddress.getByName(hostAddress).isAnyLocalAddress) {
+          conf.get("spark.driver.host", hostAddress)
+        } else {
+          hostAddress
+        }
+        val connectUri = s"$host:$bindingPort"
 
         logInfo(s"Connect service started on $connectUri")
         
@@ -1,8 +1,34 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
 use axum::response::IntoResponse;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use futures_util::future::BoxFuture;
-use http::{header::AUTHORIZATION, Request, Response, StatusCode};
-use log::info;
+use http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    Request, Response, StatusCode,
+};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use rustls_pki_types::CertificateDer;
+use serde_json::Value;
 use tower_http::auth::AsyncAuthorizeRequest;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName};
+
+use crate::{
+    config::{AuthenticatorConfig, CertIdentity, JwtConfig, ProxyConfig},
+    warm::WARM_USERNAME,
+};
+
+const DEFAULT_JWKS_REFRESH_SECS: u64 = 3600;
 
 #[derive(Clone)]
 pub struct UserId(pub String);
@@ -10,8 +36,350 @@ pub struct UserId(pub String);
 #[derive(Clone)]
 pub struct BearerToken(pub String);
 
+/// The verified certificate presented by the client during the TLS handshake
 #[derive(Clone)]
-pub struct UserAuth {}
+pub struct ClientCertificate(pub CertificateDer<'static>);
+
+/// Verifies the credentials on a request to the session API
+pub trait Authenticator: Send + Sync {
+    /// Returns the authenticated user, or `None` if the request doesn't carry valid
+    /// credentials for this authenticator
+    fn authenticate(&self, request: &Request<axum::body::Body>) -> Option<UserId>;
+}
+
+/// Splits an `Authorization` header into its scheme and credentials
+fn authorization(request: &Request<axum::body::Body>) -> Option<(&str, &str)> {
+    request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .split_once(' ')
+}
+
+/// HTTP Basic authentication against an htpasswd file. Only bcrypt hashes are supported.
+pub struct HtpasswdAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl HtpasswdAuthenticator {
+    pub fn from_file(path: &str) -> Self {
+        let contents = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read htpasswd file {}: {}", path, e));
+
+        let users = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (username, hash) = line
+                    .split_once(':')
+                    .unwrap_or_else(|| panic!("Invalid line in htpasswd file {}", path));
+                assert!(
+                    hash.starts_with("$2"),
+                    "Only bcrypt hashes are supported in htpasswd file {}, found invalid hash for user {}",
+                    path,
+                    username
+                );
+                (username.to_string(), hash.to_string())
+            })
+            .collect();
+
+        Self { users }
+    }
+}
+
+impl Authenticator for HtpasswdAuthenticator {
+    fn authenticate(&self, request: &Request<axum::body::Body>) -> Option<UserId> {
+        let credentials = match authorization(request)? {
+            ("Basic", credentials) => credentials,
+            _ => return None,
+        };
+
+        let decoded = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        let hash = self.users.get(username)?;
+
+        match bcrypt::verify(password, hash) {
+            Ok(true) => Some(UserId(username.to_string())),
+            Ok(false) => {
+                warn!("Invalid password for user {}", username);
+                None
+            }
+            Err(e) => {
+                warn!("Failed to verify password for user {}: {}", username, e);
+                None
+            }
+        }
+    }
+}
+
+/// Static API keys passed as a bearer token
+pub struct ApiKeyAuthenticator {
+    // Map of API key to the user it belongs to
+    keys: HashMap<String, String>,
+}
+
+impl Authenticator for ApiKeyAuthenticator {
+    fn authenticate(&self, request: &Request<axum::body::Body>) -> Option<UserId> {
+        match authorization(request)? {
+            ("Bearer", key) => self.keys.get(key).map(|user| UserId(user.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Bearer JWTs signed with RS256 or ES256 by a key in a JWKS
+pub struct JwtAuthenticator {
+    jwks: Arc<RwLock<JwkSet>>,
+    validation: Validation,
+    username_claim: String,
+}
+
+impl JwtAuthenticator {
+    pub fn from_config(config: &JwtConfig) -> Self {
+        let jwks = if let Some(url) = config.jwks_url.as_ref() {
+            // Start from the cached copy, if any, and keep it up to date in the background
+            let jwks = if Path::new(&config.jwks_path).exists() {
+                read_jwks(&config.jwks_path)
+            } else {
+                JwkSet { keys: vec![] }
+            };
+            let jwks = Arc::new(RwLock::new(jwks));
+            let interval = Duration::from_secs(
+                config
+                    .jwks_refresh_interval
+                    .unwrap_or(DEFAULT_JWKS_REFRESH_SECS),
+            );
+            tokio::task::spawn(refresh_jwks(
+                url.clone(),
+                config.jwks_path.clone(),
+                interval,
+                jwks.clone(),
+            ));
+            jwks
+        } else {
+            Arc::new(RwLock::new(read_jwks(&config.jwks_path)))
+        };
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        let mut required_claims = vec!["exp"];
+        if let Some(issuer) = config.issuer.as_ref() {
+            validation.set_issuer(&[issuer]);
+            required_claims.push("iss");
+        }
+        if let Some(audience) = config.audience.as_ref() {
+            validation.set_audience(audience);
+            required_claims.push("aud");
+        } else {
+            validation.validate_aud = false;
+        }
+        validation.set_required_spec_claims(&required_claims);
+
+        Self {
+            jwks,
+            validation,
+            username_claim: config
+                .username_claim
+                .clone()
+                .unwrap_or_else(|| "sub".to_string()),
+        }
+    }
+}
+
+fn read_jwks(path: &str) -> JwkSet {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read JWKS file {}: {}", path, e));
+    serde_json::from_str(&contents)
+        .unwrap_or_else(|e| panic!("Failed to parse JWKS file {}: {}", path, e))
+}
+
+async fn fetch_jwks(url: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+
+    let response = client.get(url.parse()?).await?;
+    if !response.status().is_success() {
+        return Err(format!("Unexpected status {}", response.status()).into());
+    }
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok(String::from_utf8(body.to_vec())?)
+}
+
+/// Periodically fetch the JWKS from `url`, caching it to `path`
+async fn refresh_jwks(url: String, path: String, interval: Duration, jwks: Arc<RwLock<JwkSet>>) {
+    loop {
+        match fetch_jwks(&url).await {
+            Ok(contents) => match serde_json::from_str::<JwkSet>(&contents) {
+                Ok(fetched) => {
+                    if let Err(e) = tokio::fs::write(&path, &contents).await {
+                        warn!("Failed to cache JWKS to {}: {}", path, e);
+                    }
+                    info!("Loaded {} keys from {}", fetched.keys.len(), url);
+                    *jwks.write().unwrap() = fetched;
+                }
+                Err(e) => warn!("Failed to parse JWKS from {}: {}", url, e),
+            },
+            Err(e) => warn!("Failed to fetch JWKS from {}: {}", url, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, request: &Request<axum::body::Body>) -> Option<UserId> {
+        let token = match authorization(request)? {
+            ("Bearer", token) => token,
+            _ => return None,
+        };
+
+        // Bearer tokens that aren't JWTs may belong to another authenticator
+        let header = decode_header(token).ok()?;
+        if !matches!(header.alg, Algorithm::RS256 | Algorithm::ES256) {
+            warn!("Unsupported JWT algorithm {:?}", header.alg);
+            return None;
+        }
+
+        let key = {
+            let jwks = self.jwks.read().unwrap();
+            let jwk = match header.kid.as_ref() {
+                Some(kid) => jwks.find(kid),
+                None if jwks.keys.len() == 1 => jwks.keys.first(),
+                None => None,
+            };
+            let Some(jwk) = jwk else {
+                warn!("No matching JWK found for key id {:?}", header.kid);
+                return None;
+            };
+            DecodingKey::from_jwk(jwk)
+                .map_err(|e| warn!("Invalid JWK {:?}: {}", header.kid, e))
+                .ok()?
+        };
+
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+
+        let claims = decode::<HashMap<String, Value>>(token, &key, &validation)
+            .map_err(|e| warn!("Invalid JWT: {}", e))
+            .ok()?
+            .claims;
+
+        match claims.get(&self.username_claim).and_then(Value::as_str) {
+            Some(username) => Some(UserId(username.to_string())),
+            None => {
+                warn!("JWT is missing username claim {}", self.username_claim);
+                None
+            }
+        }
+    }
+}
+
+/// Identifies users by their verified TLS client certificate
+pub struct ClientCertAuthenticator {
+    identity: CertIdentity,
+}
+
+impl ClientCertAuthenticator {
+    fn identity(&self, cert: &X509Certificate) -> Option<String> {
+        match self.identity {
+            CertIdentity::CommonName => cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_string),
+            CertIdentity::SubjectAltName => cert
+                .subject_alternative_name()
+                .ok()??
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::RFC822Name(name) | GeneralName::DNSName(name) => {
+                        Some(name.to_string())
+                    }
+                    _ => None,
+                }),
+        }
+    }
+}
+
+impl Authenticator for ClientCertAuthenticator {
+    fn authenticate(&self, request: &Request<axum::body::Body>) -> Option<UserId> {
+        let ClientCertificate(der) = request.extensions().get::<ClientCertificate>()?;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|e| warn!("Failed to parse client certificate: {}", e))
+            .ok()?;
+
+        match self.identity(&cert) {
+            Some(username) => Some(UserId(username)),
+            None => {
+                warn!("No identity found in client certificate {}", cert.subject());
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UserAuth {
+    authenticators: Arc<Vec<Box<dyn Authenticator>>>,
+    // Whether to challenge unauthenticated clients for Basic credentials
+    basic_challenge: bool,
+}
+
+impl UserAuth {
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        if config.authenticators.is_empty() {
+            warn!("No authenticators configured, all session API requests will be rejected");
+        }
+
+        let authenticators = config
+            .authenticators
+            .iter()
+            .map(|auth_config| -> Box<dyn Authenticator> {
+                match auth_config {
+                    AuthenticatorConfig::Htpasswd { path } => {
+                        Box::new(HtpasswdAuthenticator::from_file(path))
+                    }
+                    AuthenticatorConfig::ApiKeys { keys } => {
+                        Box::new(ApiKeyAuthenticator { keys: keys.clone() })
+                    }
+                    AuthenticatorConfig::Jwt(jwt_config) => {
+                        Box::new(JwtAuthenticator::from_config(jwt_config))
+                    }
+                    AuthenticatorConfig::ClientCert { identity } => {
+                        assert!(
+                            config
+                                .tls
+                                .as_ref()
+                                .is_some_and(|tls| tls.client_ca.is_some()),
+                            "Client certificate authentication requires tls.client_ca"
+                        );
+                        Box::new(ClientCertAuthenticator {
+                            identity: *identity,
+                        })
+                    }
+                }
+            })
+            .collect();
+
+        let basic_challenge = config
+            .authenticators
+            .iter()
+            .any(|auth_config| matches!(auth_config, AuthenticatorConfig::Htpasswd { .. }));
+
+        Self {
+            authenticators: Arc::new(authenticators),
+            basic_challenge,
+        }
+    }
+}
 
 impl AsyncAuthorizeRequest<axum::body::Body> for UserAuth {
     type RequestBody = axum::body::Body;
@@ -22,11 +390,38 @@ impl AsyncAuthorizeRequest<axum::body::Body> for UserAuth {
         BoxFuture<'static, Result<Request<Self::RequestBody>, Response<Self::ResponseBody>>>;
 
     fn authorize(&mut self, mut request: hyper::Request<axum::body::Body>) -> Self::Future {
-        Box::pin(async {
-            request
-                .extensions_mut()
-                .insert(UserId("testuser".to_string()));
-            Ok(request)
+        let authenticators = self.authenticators.clone();
+        let basic_challenge = self.basic_challenge;
+        Box::pin(async move {
+            // Password hashing is expensive, so don't stall other tasks on this worker
+            let user = tokio::task::block_in_place(|| {
+                authenticators
+                    .iter()
+                    .find_map(|authenticator| authenticator.authenticate(&request))
+            })
+            .filter(|user| {
+                // Nobody may act as the owner of the warm drivers, whatever an API key, JWT
+                // or certificate says their name is
+                let reserved = user.0 == WARM_USERNAME;
+                if reserved {
+                    warn!("Rejecting reserved username {}", user.0);
+                }
+                !reserved
+            });
+
+            if let Some(user) = user {
+                info!("Authenticated user {}", user.0);
+                request.extensions_mut().insert(user);
+                Ok(request)
+            } else if basic_challenge {
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    [(WWW_AUTHENTICATE, "Basic realm=\"spark-connect-proxy\"")],
+                )
+                    .into_response())
+            } else {
+                Err(StatusCode::UNAUTHORIZED.into_response())
+            }
         })
     }
 }
@@ -52,8 +447,6 @@ impl AsyncAuthorizeRequest<axum::body::Body> for TokenAuth {
                 .map_err(|_| StatusCode::BAD_REQUEST.into_response())?
                 .to_string();
 
-            info!("Authorizing token: {}", authorization);
-
             let split = authorization.split_once(' ');
             let token = match split {
                 Some(("Bearer", token)) => token,
@@ -67,3 +460,162 @@ impl AsyncAuthorizeRequest<axum::body::Body> for TokenAuth {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::store::now;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/jwt");
+
+    fn authenticator(issuer: Option<&str>, audience: Option<&str>) -> JwtAuthenticator {
+        JwtAuthenticator::from_config(&JwtConfig {
+            jwks_path: format!("{}/jwks.json", FIXTURES),
+            jwks_url: None,
+            jwks_refresh_interval: None,
+            issuer: issuer.map(str::to_string),
+            audience: audience.map(|audience| vec![audience.to_string()]),
+            username_claim: None,
+        })
+    }
+
+    fn sign(alg: Algorithm, kid: &str, claims: Value) -> String {
+        let pem = match alg {
+            Algorithm::RS256 => fs::read(format!("{}/rs256.pem", FIXTURES)).unwrap(),
+            _ => fs::read(format!("{}/es256.pem", FIXTURES)).unwrap(),
+        };
+        let key = match alg {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(&pem).unwrap(),
+            _ => EncodingKey::from_ec_pem(&pem).unwrap(),
+        };
+        let mut header = Header::new(alg);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    fn username(authenticator: &JwtAuthenticator, token: &str) -> Option<String> {
+        let request = Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        authenticator.authenticate(&request).map(|user| user.0)
+    }
+
+    fn claims() -> Value {
+        json!({
+            "sub": "alice",
+            "iss": "https://issuer.example.com",
+            "aud": "spark",
+            "exp": now() + 300,
+        })
+    }
+
+    #[test]
+    fn accepts_rs256() {
+        let token = sign(Algorithm::RS256, "rsa", claims());
+        assert_eq!(
+            username(&authenticator(None, None), &token).as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn accepts_es256() {
+        let token = sign(Algorithm::ES256, "ec", claims());
+        assert_eq!(
+            username(&authenticator(None, None), &token).as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn checks_issuer_and_audience() {
+        let token = sign(Algorithm::RS256, "rsa", claims());
+        let authenticator = authenticator(Some("https://issuer.example.com"), Some("spark"));
+        assert_eq!(username(&authenticator, &token).as_deref(), Some("alice"));
+
+        let mut wrong_issuer = claims();
+        wrong_issuer["iss"] = json!("https://other.example.com");
+        let token = sign(Algorithm::RS256, "rsa", wrong_issuer);
+        assert_eq!(username(&authenticator, &token), None);
+
+        let mut wrong_audience = claims();
+        wrong_audience["aud"] = json!("other");
+        let token = sign(Algorithm::RS256, "rsa", wrong_audience);
+        assert_eq!(username(&authenticator, &token), None);
+
+        let mut no_audience = claims();
+        no_audience.as_object_mut().unwrap().remove("aud");
+        let token = sign(Algorithm::RS256, "rsa", no_audience);
+        assert_eq!(username(&authenticator, &token), None);
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let mut expired = claims();
+        // Past the default leeway of a minute
+        expired["exp"] = json!(now() - 120);
+        let token = sign(Algorithm::ES256, "ec", expired);
+        assert_eq!(username(&authenticator(None, None), &token), None);
+    }
+
+    #[test]
+    fn rejects_unknown_kid() {
+        let token = sign(Algorithm::RS256, "unknown", claims());
+        assert_eq!(username(&authenticator(None, None), &token), None);
+    }
+
+    #[test]
+    fn rejects_key_of_another_algorithm() {
+        // Signed by the EC key, but claiming to be the RSA key
+        let token = sign(Algorithm::ES256, "rsa", claims());
+        assert_eq!(username(&authenticator(None, None), &token), None);
+    }
+
+    #[test]
+    fn rejects_missing_username_claim() {
+        let mut no_subject = claims();
+        no_subject.as_object_mut().unwrap().remove("sub");
+        let token = sign(Algorithm::RS256, "rsa", no_subject);
+        assert_eq!(username(&authenticator(None, None), &token), None);
+    }
+
+    #[test]
+    fn ignores_other_bearer_tokens() {
+        assert_eq!(username(&authenticator(None, None), "not-a-jwt"), None);
+    }
+
+    async fn authorize(auth: &mut UserAuth, key: &str) -> Option<String> {
+        let request = Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", key))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        auth.authorize(request)
+            .await
+            .ok()
+            .and_then(|request| request.extensions().get::<UserId>().cloned())
+            .map(|user| user.0)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_the_warm_pool_username() {
+        let config = ProxyConfig {
+            authenticators: vec![AuthenticatorConfig::ApiKeys {
+                keys: HashMap::from([
+                    ("alice-key".to_string(), "alice".to_string()),
+                    ("warm-key".to_string(), WARM_USERNAME.to_string()),
+                ]),
+            }],
+            ..Default::default()
+        };
+        let mut auth = UserAuth::from_config(&config);
+        assert_eq!(
+            authorize(&mut auth, "alice-key").await.as_deref(),
+            Some("alice")
+        );
+        assert_eq!(authorize(&mut auth, "warm-key").await, None);
+    }
+}
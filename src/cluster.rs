@@ -0,0 +1,317 @@
+/// Module for submitting drivers to cluster managers and controlling their applications
+use std::{collections::HashMap, io, path::PathBuf, process::Stdio};
+
+use log::info;
+use tokio::process::Command;
+
+use crate::config::{DeployMode, SparkVersion};
+
+/// The kind of cluster manager a master URL points at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClusterManager {
+    Local,
+    Standalone,
+    Yarn,
+    Kubernetes,
+}
+
+impl ClusterManager {
+    pub fn from_master(master: &str) -> Self {
+        if master.starts_with("spark://") {
+            Self::Standalone
+        } else if master == "yarn" {
+            Self::Yarn
+        } else if master.starts_with("k8s://") {
+            Self::Kubernetes
+        } else {
+            Self::Local
+        }
+    }
+}
+
+/// How drivers of a Spark version are submitted to a cluster manager, and how the
+/// applications they create are found, killed and checked on
+#[derive(Clone, Debug)]
+pub struct ClusterApp {
+    manager: ClusterManager,
+    deploy_mode: DeployMode,
+    master: String,
+    submit_path: PathBuf,
+    envs: HashMap<String, String>,
+}
+
+impl ClusterApp {
+    /// Drivers with a local master don't create an application, so there's nothing to
+    /// track for them
+    pub fn for_version(version: &SparkVersion) -> Option<Self> {
+        let master = version.get_master();
+        let manager = ClusterManager::from_master(&master);
+        if manager == ClusterManager::Local {
+            return None;
+        }
+        Some(Self {
+            manager,
+            deploy_mode: version.get_deploy_mode(),
+            master,
+            submit_path: PathBuf::from(&version.home).join("bin/spark-submit"),
+            envs: version.env.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Whether the driver runs on the cluster instead of under spark-submit
+    pub fn is_remote(&self) -> bool {
+        self.deploy_mode == DeployMode::Cluster
+    }
+
+    /// Add the configs a submission needs. Remote drivers are only supervised through
+    /// spark-submit, so it has to keep running for as long as the application does.
+    pub fn add_configs(&self, configs: &mut HashMap<String, String>) {
+        if !self.is_remote() {
+            return;
+        }
+        let wait_config = match self.manager {
+            ClusterManager::Standalone => "spark.standalone.submit.waitAppCompletion",
+            ClusterManager::Yarn => "spark.yarn.submit.waitAppCompletion",
+            ClusterManager::Kubernetes => "spark.kubernetes.submission.waitAppCompletion",
+            ClusterManager::Local => return,
+        };
+        configs.insert(wait_config.to_string(), "true".to_string());
+    }
+
+    /// Pull the application ID out of a line of spark-submit output, if it announces one
+    /// that can be killed or checked on
+    pub fn parse_app_id(&self, line: &str) -> Option<String> {
+        let markers: &[&str] = match (self.manager, self.deploy_mode) {
+            (ClusterManager::Standalone, DeployMode::Cluster) => &[
+                "Driver successfully submitted as ",
+                "Submission successfully created as ",
+            ],
+            (ClusterManager::Yarn, _) => &["Submitted application "],
+            // The submission ID is the driver pod as `namespace:name`
+            (ClusterManager::Kubernetes, DeployMode::Cluster) => &["submission ID "],
+            _ => &[],
+        };
+        markers.iter().find_map(|marker| {
+            let (_, rest) = line.split_once(marker)?;
+            rest.split_whitespace()
+                .next()
+                .map(|app_id| app_id.trim_end_matches(['.', ',']).to_string())
+        })
+    }
+
+    /// Ask the cluster manager to kill an application
+    pub async fn kill(&self, app_id: &str) -> Result<(), io::Error> {
+        let output = self.control_command("kill", app_id).output().await?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "Killing application {} failed with {}: {}",
+                app_id,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Ask the cluster manager for the state of an application. `None` if its answer
+    /// couldn't be understood.
+    pub async fn status(&self, app_id: &str) -> Result<Option<String>, io::Error> {
+        let output = self.control_command("status", app_id).output().await?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "Checking application {} failed with {}: {}",
+                app_id,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        // spark-submit logs the status to stderr
+        let output = [output.stdout, output.stderr].concat();
+        Ok(self.parse_status(&String::from_utf8_lossy(&output)))
+    }
+
+    fn parse_status(&self, output: &str) -> Option<String> {
+        output.lines().find_map(|line| {
+            let line = line.trim();
+            let state = match self.manager {
+                ClusterManager::Yarn => line.strip_prefix("State : ")?,
+                ClusterManager::Kubernetes => line.strip_prefix("phase: ")?,
+                // Reported as JSON by the REST submission server
+                ClusterManager::Standalone => line
+                    .strip_prefix("\"driverState\" : ")?
+                    .trim_end_matches(',')
+                    .trim_matches('"'),
+                ClusterManager::Local => return None,
+            };
+            Some(state.trim().to_string())
+        })
+    }
+
+    fn control_command(&self, action: &str, app_id: &str) -> Command {
+        let mut command = match self.manager {
+            ClusterManager::Yarn => {
+                let yarn = self
+                    .envs
+                    .get("HADOOP_HOME")
+                    .map(|home| PathBuf::from(home).join("bin/yarn"))
+                    .unwrap_or_else(|| PathBuf::from("yarn"));
+                let mut command = Command::new(yarn);
+                command.args(["application", &format!("-{}", action), app_id]);
+                command
+            }
+            _ => {
+                let mut command = Command::new(&self.submit_path);
+                command.args(["--master", &self.master, &format!("--{}", action), app_id]);
+                command
+            }
+        };
+        info!("Running {:?}", command.as_std());
+        command
+            .envs(&self.envs)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster_app(manager: ClusterManager, deploy_mode: DeployMode) -> ClusterApp {
+        ClusterApp {
+            manager,
+            deploy_mode,
+            master: String::new(),
+            submit_path: PathBuf::from("spark-submit"),
+            envs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_standalone_app_ids() {
+        let app = cluster_app(ClusterManager::Standalone, DeployMode::Cluster);
+        assert_eq!(
+            app.parse_app_id(
+                "24/05/01 10:00:00 INFO Client: Driver successfully submitted as driver-20240501100000-0001"
+            )
+            .as_deref(),
+            Some("driver-20240501100000-0001")
+        );
+        assert_eq!(
+            app.parse_app_id(
+                "24/05/01 10:00:00 INFO RestSubmissionClient: Submission successfully created as driver-20240501100000-0002. Polling submission state..."
+            )
+            .as_deref(),
+            Some("driver-20240501100000-0002")
+        );
+        assert_eq!(
+            app.parse_app_id("24/05/01 10:00:00 INFO SparkContext: Running Spark version 3.5.1"),
+            None
+        );
+
+        // Client mode drivers run under spark-submit, so there's nothing to track
+        let client = cluster_app(ClusterManager::Standalone, DeployMode::Client);
+        assert_eq!(
+            client.parse_app_id(
+                "24/05/01 10:00:00 INFO Client: Driver successfully submitted as driver-20240501100000-0001"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_yarn_app_ids() {
+        for deploy_mode in [DeployMode::Client, DeployMode::Cluster] {
+            let app = cluster_app(ClusterManager::Yarn, deploy_mode);
+            assert_eq!(
+                app.parse_app_id(
+                    "24/05/01 10:00:00 INFO YarnClientImpl: Submitted application application_1714557600000_0042"
+                )
+                .as_deref(),
+                Some("application_1714557600000_0042")
+            );
+        }
+    }
+
+    #[test]
+    fn parses_kubernetes_app_ids() {
+        let app = cluster_app(ClusterManager::Kubernetes, DeployMode::Cluster);
+        assert_eq!(
+            app.parse_app_id(
+                "24/05/01 10:00:00 INFO LoggingPodStatusWatcherImpl: Deployed Spark application spark-connect with application ID spark-4c1f and submission ID spark:spark-connect-driver into Kubernetes"
+            )
+            .as_deref(),
+            Some("spark:spark-connect-driver")
+        );
+        assert_eq!(
+            app.parse_app_id(
+                "24/05/01 10:00:00 INFO KubernetesClientUtils: Spark configuration files loaded"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_standalone_status() {
+        let app = cluster_app(ClusterManager::Standalone, DeployMode::Cluster);
+        let output = r#"24/05/01 10:05:00 INFO RestSubmissionClient: Submitting a request for the status of submission driver-20240501100000-0002 in spark://master:6066.
+24/05/01 10:05:00 INFO RestSubmissionClient: Server responded with SubmissionStatusResponse:
+{
+  "action" : "SubmissionStatusResponse",
+  "driverState" : "RUNNING",
+  "serverSparkVersion" : "3.5.1",
+  "submissionId" : "driver-20240501100000-0002",
+  "success" : true,
+  "workerHostPort" : "10.0.0.5:34567",
+  "workerId" : "worker-20240501095000-10.0.0.5-34567"
+}"#;
+        assert_eq!(app.parse_status(output).as_deref(), Some("RUNNING"));
+    }
+
+    #[test]
+    fn parses_yarn_status() {
+        let app = cluster_app(ClusterManager::Yarn, DeployMode::Cluster);
+        let output = "Application Report : \n\
+            \tApplication-Id : application_1714557600000_0042\n\
+            \tApplication-Name : spark-connect\n\
+            \tApplication-Type : SPARK\n\
+            \tUser : alice\n\
+            \tQueue : default\n\
+            \tStart-Time : 1714557600000\n\
+            \tFinish-Time : 0\n\
+            \tProgress : 10%\n\
+            \tState : RUNNING\n\
+            \tFinal-State : UNDEFINED\n";
+        assert_eq!(app.parse_status(output).as_deref(), Some("RUNNING"));
+    }
+
+    #[test]
+    fn parses_kubernetes_status() {
+        let app = cluster_app(ClusterManager::Kubernetes, DeployMode::Cluster);
+        let output = "Application status (driver): \n\
+            \t pod name: spark-connect-driver\n\
+            \t namespace: spark\n\
+            \t labels: spark-app-selector -> spark-4c1f, spark-role -> driver\n\
+            \t pod uid: 0b6a7c52-7e0a-4c8e-9d43-6f1c0f6d2b1a\n\
+            \t creation time: 2024-05-01T10:00:00Z\n\
+            \t service account name: spark\n\
+            \t phase: Running\n\
+            \t container status: \n\
+            \t\t container name: spark-kubernetes-driver\n";
+        assert_eq!(app.parse_status(output).as_deref(), Some("Running"));
+    }
+
+    #[test]
+    fn ignores_unknown_status_output() {
+        for manager in [
+            ClusterManager::Standalone,
+            ClusterManager::Yarn,
+            ClusterManager::Kubernetes,
+        ] {
+            let app = cluster_app(manager, DeployMode::Cluster);
+            assert_eq!(app.parse_status("Application not found"), None);
+        }
+    }
+}
@@ -1,13 +1,116 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use figment::{
     providers::{Format, Json},
     Figment,
 };
 use local_ip_address::local_ip;
+use regex::Regex;
 use serde::Deserialize;
 
 const DEFAULT_PORT: u16 = 8100;
+const DEFAULT_STOP_GRACE_PERIOD: u64 = 10;
+const DEFAULT_READY_TIMEOUT: u64 = 60;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartOn {
+    // Only restart drivers that failed
+    Failure,
+    // Restart drivers whenever they exit, unless the session is being stopped
+    Exit,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RestartPolicy {
+    pub restart_on: RestartOn,
+    pub max_restarts: u32,
+    // Seconds to wait before each restart
+    pub backoff: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployMode {
+    // The driver runs under the proxy's spark-submit process
+    #[default]
+    Client,
+    // The driver runs on the cluster and calls back from a remote host
+    Cluster,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenDelivery {
+    // Set as SPARK_CONNECT_PROXY_TOKEN in spark-submit's environment
+    Env,
+    // Written to a file only the driver's user can read, which is shipped along with
+    // `--files` in cluster deploy mode. Command templates can pass it on with
+    // `{token_file}`
+    File,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Impersonation {
+    // Pass `--proxy-user`, for Kerberized clusters where the proxy's principal is allowed
+    // to impersonate users
+    ProxyUser,
+    // Run spark-submit as the user's local account. Without a helper the proxy has to run
+    // as root. A helper like `["sudo", "-n", "-u", "{username}", "--"]` is put in front of
+    // spark-submit instead
+    RunAs { helper: Option<Vec<String>> },
+}
+
+/// A regex a whole config value has to match, compiled when the config is loaded
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ValueRegex {
+    pub pattern: String,
+    pub regex: Regex,
+}
+
+impl TryFrom<String> for ValueRegex {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))?;
+        Ok(Self { pattern, regex })
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ValueRule {
+    // The whole value must match the regex
+    Regex {
+        pattern: ValueRegex,
+    },
+    // The value must be a number within the bounds
+    Range {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    // The value must be a size like `4g` within the bounds, which are sizes too
+    Memory {
+        min: Option<String>,
+        max: Option<String>,
+    },
+}
+
+/// Which configs users may set when creating a session. Key patterns ending in `*` match
+/// any key with that prefix.
+#[derive(Clone, Default, Deserialize)]
+pub struct ConfigPolicy {
+    // Every key is allowed when unset
+    pub allowed: Option<Vec<String>>,
+    // Takes precedence over `allowed`
+    #[serde(default)]
+    pub denied: Vec<String>,
+    // Rules for the values of the keys matching each pattern
+    #[serde(default)]
+    pub values: HashMap<String, ValueRule>,
+}
 
 #[derive(Clone, Default, Deserialize)]
 pub struct SparkVersion {
@@ -20,12 +123,165 @@ pub struct SparkVersion {
     pub default_configs: Option<HashMap<String, String>>,
     pub merge_configs: Option<HashMap<String, String>>,
     pub override_configs: Option<HashMap<String, String>>,
+    // Drivers aren't restarted unless a policy is set
+    pub restart_policy: Option<RestartPolicy>,
+    // Seconds without any calls before a session is stopped
+    pub idle_timeout: Option<u64>,
+    // Master URL passed to spark-submit, defaults to `local`
+    pub master: Option<String>,
+    pub deploy_mode: Option<DeployMode>,
+    // Callback address for drivers of this version, for cluster drivers that reach the
+    // proxy by a different address
+    pub callback_address: Option<String>,
+    // Plugin jar the drivers run with, found for the Scala version by default. Required
+    // for Scala versions other than 2.13, which the plugin isn't built for
+    pub plugin_jar: Option<String>,
+    // Detected from the jars in `home` by default
+    pub scala_version: Option<String>,
+    // Drivers run as the proxy's user unless set
+    pub impersonation: Option<Impersonation>,
+    // Users can set any config unless set
+    pub config_policy: Option<ConfigPolicy>,
+    // Concurrent sessions using this version
+    pub max_sessions: Option<usize>,
+    // Drivers kept started with the default configs, ready to hand to new sessions
+    pub warm_pool_size: Option<usize>,
+    // How drivers are given their token. Defaults to `file` in cluster deploy mode, when
+    // run through a helper or with a command template, which don't pass the environment
+    // on, and `env` otherwise
+    pub token_delivery: Option<TokenDelivery>,
+}
+
+impl SparkVersion {
+    pub fn get_master(&self) -> String {
+        self.master.clone().unwrap_or_else(|| "local".to_string())
+    }
+
+    pub fn get_deploy_mode(&self) -> DeployMode {
+        self.deploy_mode.unwrap_or_default()
+    }
 }
 
 #[derive(Deserialize)]
 pub struct TlsConfig {
     pub key: String,
     pub cert: String,
+    // CA bundle used to verify client certificates. Enables client certificate auth
+    pub client_ca: Option<String>,
+    // Reject connections without a client certificate. Not supported, since Spark
+    // callbacks don't present one, and refused when the config is loaded
+    #[serde(default)]
+    pub client_cert_required: bool,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct JwtConfig {
+    // Local JWKS file. If `jwks_url` is set, fetched keys are cached here
+    pub jwks_path: String,
+    pub jwks_url: Option<String>,
+    // Seconds between refetching `jwks_url`
+    pub jwks_refresh_interval: Option<u64>,
+    // Required `iss` claim
+    pub issuer: Option<String>,
+    // Accepted `aud` claims
+    pub audience: Option<Vec<String>>,
+    // Claim used as the username, defaults to `sub`
+    pub username_claim: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthenticatorConfig {
+    // HTTP Basic auth checked against an htpasswd file of bcrypt hashes
+    Htpasswd {
+        path: String,
+    },
+    // Static API keys sent as a bearer token, mapped to the user that owns them
+    ApiKeys {
+        keys: HashMap<String, String>,
+    },
+    // RS256/ES256 bearer JWTs verified against a JWKS
+    Jwt(JwtConfig),
+    // Verified TLS client certificates, requires `tls.client_ca`
+    ClientCert {
+        #[serde(default)]
+        identity: CertIdentity,
+    },
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertIdentity {
+    // The subject common name
+    #[default]
+    CommonName,
+    // The first email or DNS subject alternative name
+    SubjectAltName,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionStoreConfig {
+    // Sessions are lost when the proxy restarts
+    #[default]
+    Memory,
+    // Sessions are persisted to a SQLite database at `path`
+    Sqlite {
+        path: String,
+    },
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LauncherConfig {
+    // Run spark-submit directly
+    #[default]
+    SparkSubmit,
+    // Run a command wrapping spark-submit. `{args}` expands to spark-submit's arguments,
+    // and `{spark_submit}`, `{spark_home}`, `{version}`, `{session_id}` and `{username}`
+    // are substituted
+    CommandTemplate {
+        command: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteBy {
+    // The bearer token in the authorization header
+    Token,
+    // The Spark Connect session ID, so clients can connect with `;session_id=<token>`
+    SessionId,
+    // The Spark Connect user ID, so clients can connect with `;user_id=<token>`
+    UserId,
+}
+
+/// Limits on the sessions that can run at once. Sessions count until they stop or fail.
+#[derive(Clone, Default, Deserialize)]
+pub struct QuotaConfig {
+    // Concurrent sessions across all users
+    pub max_sessions: Option<usize>,
+    // Concurrent sessions for each user
+    pub max_sessions_per_user: Option<usize>,
+    // Total `spark.driver.memory` across sessions, as a size like `64g`
+    pub max_driver_memory: Option<String>,
+    // Total `spark.driver.cores` across sessions
+    pub max_driver_cores: Option<u64>,
+    // Hold sessions over the limits until there's capacity instead of rejecting them
+    #[serde(default)]
+    pub queue: bool,
+    // Sessions that can wait in the queue at once, unlimited by default
+    pub max_queued: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct DriverLogConfig {
+    // Directory driver logs are written to, defaults to a temp directory
+    pub dir: Option<String>,
+    // Size in bytes a log file can reach before it's rotated
+    pub max_bytes: Option<u64>,
+    // Number of rotated log files kept per session
+    pub max_files: Option<usize>,
 }
 
 #[derive(Deserialize, Default)]
@@ -34,21 +290,86 @@ pub struct ProxyConfig {
     pub bind_port: Option<u16>,
     pub callback_address: Option<String>,
     pub tls: Option<TlsConfig>,
+    #[serde(default)]
     pub spark_versions: Vec<SparkVersion>,
+    // Directory searched for plugin jars before the sbt output, and where bundled jars
+    // are extracted, which then must be owned by the proxy's user and not writable by
+    // others. Bundled jars are extracted to a new temp directory each run by default
+    pub plugin_dir: Option<String>,
+    // Authenticators for the session API, tried in order
+    #[serde(default)]
+    pub authenticators: Vec<AuthenticatorConfig>,
+    #[serde(default)]
+    pub session_store: SessionStoreConfig,
+    // How drivers are launched, defaults to running spark-submit directly
+    #[serde(default)]
+    pub launcher: LauncherConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    // Seconds to wait after SIGTERM before killing a driver
+    pub stop_grace_period: Option<u64>,
+    #[serde(default)]
+    pub driver_logs: DriverLogConfig,
+    // Seconds without any calls before a session is stopped, unless the user or
+    // version sets its own. Sessions never time out by default
+    pub idle_timeout: Option<u64>,
+    // Idle timeouts for specific users, taking precedence over the version's
+    #[serde(default)]
+    pub user_idle_timeouts: HashMap<String, u64>,
+    // Seconds Spark Connect calls and waiting API requests are held while a session
+    // is starting up
+    pub ready_timeout: Option<u64>,
+    // Seconds a session token is accepted for before it has to be rotated. Tokens never
+    // expire by default
+    pub token_ttl: Option<u64>,
+    // Directory driver token files are written to, which must be owned by the proxy's
+    // user and not writable by others. Defaults to a new temp directory each run
+    pub token_dir: Option<String>,
+    // Where the session token for each Spark Connect call is looked for, in order.
+    // Defaults to only the authorization header
+    pub route_by: Option<Vec<RouteBy>>,
 }
 
 impl ProxyConfig {
     pub fn from_file(path: impl AsRef<str>) -> Self {
-        Figment::new()
+        let config: Self = Figment::new()
             .merge(Json::file(path.as_ref()))
             .extract()
-            .unwrap()
+            .unwrap();
+        config.validate();
+        config
+    }
+
+    /// Check for settings that can't work, panicking if any are set
+    fn validate(&self) {
+        assert!(
+            !self
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.client_cert_required),
+            "tls.client_cert_required isn't supported, drivers calling back to the proxy don't \
+            present a client certificate"
+        );
     }
 
     pub fn get_bind_port(&self) -> u16 {
         self.bind_port.unwrap_or(DEFAULT_PORT)
     }
 
+    pub fn get_stop_grace_period(&self) -> Duration {
+        Duration::from_secs(self.stop_grace_period.unwrap_or(DEFAULT_STOP_GRACE_PERIOD))
+    }
+
+    pub fn get_ready_timeout(&self) -> Duration {
+        Duration::from_secs(self.ready_timeout.unwrap_or(DEFAULT_READY_TIMEOUT))
+    }
+
+    pub fn get_route_by(&self) -> Vec<RouteBy> {
+        self.route_by
+            .clone()
+            .unwrap_or_else(|| vec![RouteBy::Token])
+    }
+
     pub fn get_callback_addr(&self) -> String {
         self.callback_address.clone().unwrap_or_else(|| {
             let callback_scheme = if self.tls.is_some() { "https" } else { "http" };
@@ -65,3 +386,28 @@ impl ProxyConfig {
     //     self.config.get_table(key)
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "tls.client_cert_required isn't supported")]
+    fn refuses_required_client_certificates() {
+        let path = env::temp_dir().join(format!("spark-connect-proxy-{}.json", Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"{"tls": {"key": "key.pem", "cert": "cert.pem", "client_cert_required": true}}"#,
+        )
+        .unwrap();
+        let result = std::panic::catch_unwind(|| {
+            ProxyConfig::from_file(path.to_str().unwrap());
+        });
+        fs::remove_file(&path).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+}
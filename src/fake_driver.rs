@@ -0,0 +1,112 @@
+/// Module for a stand-in Spark driver that tests proxy calls to
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::body::Body;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Request, Response,
+};
+use http_body_util::BodyExt;
+use hyper::{body::Incoming, server::conn::http2, service::service_fn};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// How the fake driver handles each call
+#[derive(Clone, Copy)]
+pub enum Behavior {
+    // Echo the request body back after a delay
+    Respond(Duration),
+    // Respond, then close the connection with a GOAWAY
+    GoAway,
+    // Drop the connection without responding
+    Reset,
+}
+
+/// An HTTP/2 server answering calls like a Spark driver would
+pub struct FakeDriver {
+    pub addr: String,
+    connections: Arc<AtomicUsize>,
+}
+
+impl FakeDriver {
+    pub async fn start(behavior: Behavior) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                accepted.fetch_add(1, Ordering::Relaxed);
+                tokio::task::spawn(serve(stream, behavior));
+            }
+        });
+        Self { addr, connections }
+    }
+
+    /// Connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+async fn serve(stream: TcpStream, behavior: Behavior) {
+    // Signalled by each call, so the connection can be closed around it
+    let (called_tx, mut called_rx) = mpsc::unbounded_channel();
+    let service = service_fn(move |req: Request<Incoming>| {
+        let _ = called_tx.send(());
+        respond(req, behavior)
+    });
+    let conn =
+        http2::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+
+    tokio::select! {
+        _ = conn.as_mut() => return,
+        _ = called_rx.recv() => (),
+    }
+    match behavior {
+        Behavior::Respond(_) => {
+            let _ = conn.await;
+        }
+        Behavior::GoAway => {
+            conn.as_mut().graceful_shutdown();
+            let _ = conn.await;
+        }
+        // Dropping the connection closes the socket with the call still open
+        Behavior::Reset => (),
+    }
+}
+
+async fn respond(req: Request<Incoming>, behavior: Behavior) -> Result<Response<Body>, Infallible> {
+    let delay = match behavior {
+        Behavior::Respond(delay) => delay,
+        Behavior::GoAway => Duration::ZERO,
+        Behavior::Reset => return Ok(std::future::pending().await),
+    };
+    let authorization = req.headers().get(AUTHORIZATION).cloned();
+    let body = req.into_body().collect().await.map(|body| body.to_bytes());
+    tokio::time::sleep(delay).await;
+
+    let mut response = Response::builder()
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("grpc-status", 0);
+    if let Some(authorization) = authorization {
+        // Lets tests check the token the driver was called with
+        response = response.header("x-authorization", authorization);
+    }
+    Ok(response.body(Body::from(body.unwrap_or_default())).unwrap())
+}
@@ -0,0 +1,107 @@
+/// Module for a stand-in launcher that tests session handling with
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+use crate::{
+    launcher::Launcher,
+    policy::RejectedConfig,
+    store::{Session, SessionState, SessionStore},
+};
+
+/// Moves sessions through the states a real driver would without starting any, and
+/// records what it was asked to do
+pub struct FakeLauncher {
+    session_store: Arc<dyn SessionStore>,
+    // How long stopping a driver takes
+    stop_delay: Duration,
+    pub launched: Mutex<Vec<u64>>,
+    pub stopped: Mutex<Vec<u64>>,
+}
+
+impl FakeLauncher {
+    pub fn new(session_store: Arc<dyn SessionStore>, stop_delay: Duration) -> Self {
+        Self {
+            session_store,
+            stop_delay,
+            launched: Mutex::new(Vec::new()),
+            stopped: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Launcher for FakeLauncher {
+    fn get_versions(&self) -> Vec<String> {
+        vec!["default".to_string()]
+    }
+
+    fn check_config(
+        &self,
+        _version_name: Option<&str>,
+        _user_config: &HashMap<String, String>,
+    ) -> Vec<RejectedConfig> {
+        Vec::new()
+    }
+
+    fn effective_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: HashMap<String, String>,
+    ) -> Result<(String, HashMap<String, String>), io::Error> {
+        Ok((version_name.unwrap_or("default").to_string(), user_config))
+    }
+
+    async fn launch(
+        &self,
+        session_id: u64,
+        _version_name: Option<&str>,
+        _username: String,
+        _user_config: HashMap<String, String>,
+    ) -> Result<(), io::Error> {
+        let launching = self
+            .session_store
+            .set_session_state(session_id, SessionState::Launching)
+            .await
+            .map_err(io::Error::other)?;
+        if !launching {
+            return Err(io::Error::other("Session can't be launched"));
+        }
+        self.launched.lock().unwrap().push(session_id);
+        Ok(())
+    }
+
+    async fn stop(&self, session_id: u64) -> Result<(), io::Error> {
+        self.session_store
+            .set_session_state(session_id, SessionState::Stopping)
+            .await
+            .map_err(io::Error::other)?;
+        tokio::time::sleep(self.stop_delay).await;
+        self.session_store
+            .set_session_state(session_id, SessionState::Stopped)
+            .await
+            .map_err(io::Error::other)?;
+        self.stopped.lock().unwrap().push(session_id);
+        Ok(())
+    }
+
+    async fn status(&self, _session: &Session) -> Result<Option<String>, io::Error> {
+        Ok(None)
+    }
+
+    async fn logs(&self, _session_id: u64, _tail: Option<usize>) -> Result<Vec<String>, io::Error> {
+        Ok(Vec::new())
+    }
+
+    fn follow_logs(&self, _session_id: u64) -> Option<broadcast::Receiver<String>> {
+        None
+    }
+
+    async fn remove_logs(&self, _session_id: u64) {}
+}
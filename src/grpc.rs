@@ -0,0 +1,211 @@
+/// Module for the bits of gRPC the proxy needs to understand itself
+use http::{header::CONTENT_TYPE, HeaderValue, Response, StatusCode};
+
+// Length of the prefix in front of each gRPC message: a compression flag and a length
+pub const MESSAGE_PREFIX_LEN: usize = 5;
+
+/// The gRPC status codes the proxy returns itself
+#[derive(Clone, Copy, Debug)]
+pub enum Code {
+    NotFound = 5,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+/// Build a trailers-only gRPC response carrying an error status, so clients see the
+/// message instead of an opaque transport error
+pub fn error_response(code: Code, message: &str) -> Response<axum::body::Body> {
+    let mut response = Response::new(axum::body::Body::empty());
+    *response.status_mut() = StatusCode::OK;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.insert("grpc-status", HeaderValue::from(code as i32));
+    if let Ok(message) = HeaderValue::from_str(&encode_message(message)) {
+        headers.insert("grpc-message", message);
+    }
+    response
+}
+
+/// Percent-encode a status message as the gRPC spec requires
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Length of the first message in a gRPC body once enough of it has been read to tell.
+/// Compressed messages can't be inspected, so report `None` for them.
+pub fn message_len(body: &[u8]) -> Option<usize> {
+    let prefix = body.get(..MESSAGE_PREFIX_LEN)?;
+    if prefix[0] != 0 {
+        return None;
+    }
+    Some(u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize)
+}
+
+/// The fields every Spark Connect request message starts with
+#[derive(Default, Debug)]
+pub struct RequestContext {
+    // Field 1, the Spark Connect session ID
+    pub session_id: Option<String>,
+    // Field 1 of the `user_context` message in field 2
+    pub user_id: Option<String>,
+}
+
+impl RequestContext {
+    /// Pull the context out of an encoded request message. Parsing stops at the first
+    /// thing it doesn't understand, so a truncated message still yields its leading fields.
+    pub fn parse(message: &[u8]) -> Self {
+        let mut context = Self::default();
+        for (field, value) in Fields(message) {
+            match field {
+                1 => context.session_id = String::from_utf8(value.to_vec()).ok(),
+                2 => {
+                    context.user_id = Fields(value)
+                        .find(|(field, _)| *field == 1)
+                        .and_then(|(_, user_id)| String::from_utf8(user_id.to_vec()).ok())
+                }
+                _ => (),
+            }
+        }
+        context
+    }
+}
+
+/// Iterates over the length-delimited fields of a protobuf message, skipping the rest
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = self.0.split_first()?;
+            self.0 = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (skipped, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(skipped)
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (u64, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.varint()?;
+            match key & 0x7 {
+                0 => {
+                    self.varint()?;
+                }
+                1 => {
+                    self.skip(8)?;
+                }
+                2 => {
+                    let len = self.varint()? as usize;
+                    return Some((key >> 3, self.skip(len)?));
+                }
+                5 => {
+                    self.skip(4)?;
+                }
+                // Groups are deprecated and never used by Spark Connect
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_response_is_trailers_only() {
+        let response = error_response(Code::Unavailable, "Session 1 failed: 100% ✗");
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[CONTENT_TYPE], "application/grpc");
+        assert_eq!(headers["grpc-status"], "14");
+        assert_eq!(
+            headers["grpc-message"],
+            "Session 1 failed: 100%25 %E2%9C%97"
+        );
+    }
+
+    #[test]
+    fn message_len_reads_the_prefix() {
+        assert_eq!(message_len(&[]), None);
+        assert_eq!(message_len(&[0, 0, 0, 1]), None);
+        assert_eq!(message_len(&[0, 0, 0, 1, 2]), Some(258));
+        assert_eq!(message_len(&[0, 0, 0, 0, 3, 1, 2, 3]), Some(3));
+        // Compressed
+        assert_eq!(message_len(&[1, 0, 0, 0, 3, 1, 2, 3]), None);
+    }
+
+    fn field(number: u8, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![number << 3 | 2, value.len() as u8];
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    #[test]
+    fn parses_session_and_user_id() {
+        let mut user_context = field(2, b"ignored");
+        user_context.extend(field(1, b"alice"));
+        let mut message = field(1, b"session");
+        message.extend(field(2, &user_context));
+        // A plan the context doesn't care about
+        message.extend(field(3, b"plan"));
+
+        let context = RequestContext::parse(&message);
+        assert_eq!(context.session_id.as_deref(), Some("session"));
+        assert_eq!(context.user_id.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn skips_other_wire_types() {
+        // A varint, a 64-bit and a 32-bit field ahead of the session ID
+        let mut message = vec![3 << 3, 0x96, 0x01, 4 << 3 | 1];
+        message.extend([0; 8]);
+        message.push(5 << 3 | 5);
+        message.extend([0; 4]);
+        message.extend(field(1, b"session"));
+
+        let context = RequestContext::parse(&message);
+        assert_eq!(context.session_id.as_deref(), Some("session"));
+        assert_eq!(context.user_id, None);
+    }
+
+    #[test]
+    fn parses_truncated_messages() {
+        let mut message = field(1, b"session");
+        message.extend(field(2, &field(1, b"alice")));
+        message.truncate(message.len() - 2);
+
+        let context = RequestContext::parse(&message);
+        assert_eq!(context.session_id.as_deref(), Some("session"));
+        assert_eq!(context.user_id, None);
+
+        assert_eq!(RequestContext::parse(&[0x0a, 0x80]).session_id, None);
+        // Invalid UTF-8
+        assert_eq!(RequestContext::parse(&field(1, &[0xff])).session_id, None);
+    }
+}
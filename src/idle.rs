@@ -0,0 +1,225 @@
+/// Module for shutting down sessions that stop receiving traffic
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::ProxyConfig,
+    store::{SessionState, SessionStore},
+    supervisor::Supervisor,
+};
+
+// How often sessions are checked for going idle
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Activity {
+    username: String,
+    timeout: Duration,
+    last_active: Instant,
+    // Calls sent to the driver whose responses haven't ended yet
+    in_flight: usize,
+}
+
+/// Counts a call as in flight for as long as it's held
+pub struct CallGuard {
+    idle_sessions: Arc<IdleSessions>,
+    session_id: u64,
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        if let Some(activity) = self
+            .idle_sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&self.session_id)
+        {
+            // The session may have been tracked anew while the call was in flight
+            activity.in_flight = activity.in_flight.saturating_sub(1);
+            activity.last_active = Instant::now();
+        }
+    }
+}
+
+/// Tracks the last time each session was sent a call through the proxy, and stops the
+/// ones that have gone quiet for longer than their idle timeout
+pub struct IdleSessions {
+    default_timeout: Option<u64>,
+    user_timeouts: HashMap<String, u64>,
+    // Sessions with an idle timeout by session ID
+    sessions: Mutex<HashMap<u64, Activity>>,
+}
+
+impl IdleSessions {
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        Self {
+            default_timeout: config.idle_timeout,
+            user_timeouts: config.user_idle_timeouts.clone(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start tracking a newly launched session. A timeout configured for the user takes
+    /// precedence over one for the Spark version, which takes precedence over the default.
+    pub fn track(&self, session_id: u64, username: &str, version_timeout: Option<u64>) {
+        let timeout = self
+            .user_timeouts
+            .get(username)
+            .copied()
+            .or(version_timeout)
+            .or(self.default_timeout);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        match timeout {
+            Some(timeout) => {
+                sessions.insert(
+                    session_id,
+                    Activity {
+                        username: username.to_string(),
+                        timeout: Duration::from_secs(timeout),
+                        last_active: Instant::now(),
+                        in_flight: 0,
+                    },
+                );
+            }
+            None => {
+                sessions.remove(&session_id);
+            }
+        }
+    }
+
+    /// Restart a session's idle time
+    pub fn touch(&self, session_id: u64) {
+        if let Some(activity) = self.sessions.lock().unwrap().get_mut(&session_id) {
+            activity.last_active = Instant::now();
+        }
+    }
+
+    /// Record the start of a call to a session. The session isn't idle until the
+    /// returned guard is dropped, which should be once the call's response has ended, so
+    /// a long running ExecutePlan stream keeps it alive.
+    pub fn start_call(self: &Arc<Self>, session_id: u64) -> CallGuard {
+        if let Some(activity) = self.sessions.lock().unwrap().get_mut(&session_id) {
+            activity.in_flight += 1;
+            activity.last_active = Instant::now();
+        }
+        CallGuard {
+            idle_sessions: self.clone(),
+            session_id,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_tracked(&self, session_id: u64) -> bool {
+        self.sessions.lock().unwrap().contains_key(&session_id)
+    }
+
+    pub fn forget(&self, session_id: u64) {
+        self.sessions.lock().unwrap().remove(&session_id);
+    }
+
+    /// Periodically stop ready sessions that have been idle for too long
+    pub fn spawn_reaper(
+        self: &Arc<Self>,
+        session_store: Arc<dyn SessionStore>,
+        supervisor: Arc<Supervisor>,
+    ) {
+        let idle_sessions = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                idle_sessions.reap(&session_store, &supervisor).await;
+            }
+        });
+    }
+
+    /// Sessions without calls in flight that have been idle for longer than their timeout,
+    /// with the user each belongs to
+    fn expired(&self) -> Vec<(u64, String)> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, activity)| {
+                activity.in_flight == 0 && activity.last_active.elapsed() >= activity.timeout
+            })
+            .map(|(id, activity)| (*id, activity.username.clone()))
+            .collect()
+    }
+
+    async fn reap(&self, session_store: &Arc<dyn SessionStore>, supervisor: &Arc<Supervisor>) {
+        for (session_id, username) in self.expired() {
+            let session = match session_store.get_session(&username, session_id).await {
+                Ok(Some(session)) => session,
+                Ok(None) => {
+                    self.forget(session_id);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to look up session {}: {}", session_id, e);
+                    continue;
+                }
+            };
+
+            // Sessions that are still launching or already shutting down are left alone
+            if session.state != SessionState::Ready {
+                continue;
+            }
+
+            match session_store
+                .set_session_state(session_id, SessionState::Idle)
+                .await
+            {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("Failed to update state of session {}: {}", session_id, e);
+                    continue;
+                }
+            }
+
+            info!("Stopping idle session {}", session_id);
+            let supervisor = supervisor.clone();
+            tokio::task::spawn(async move {
+                if let Err(e) = supervisor.stop(session_id).await {
+                    warn!("Failed to stop idle session {}: {}", session_id, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_in_flight_keep_sessions_alive() {
+        let idle_sessions = Arc::new(IdleSessions::from_config(&ProxyConfig::default()));
+        idle_sessions.track(1, "alice", Some(0));
+        assert_eq!(idle_sessions.expired(), vec![(1, "alice".to_string())]);
+
+        let first = idle_sessions.start_call(1);
+        let second = idle_sessions.start_call(1);
+        assert!(idle_sessions.expired().is_empty());
+        drop(first);
+        assert!(idle_sessions.expired().is_empty());
+        drop(second);
+        assert_eq!(idle_sessions.expired(), vec![(1, "alice".to_string())]);
+    }
+
+    #[test]
+    fn calls_to_untracked_sessions_are_ignored() {
+        let idle_sessions = Arc::new(IdleSessions::from_config(&ProxyConfig::default()));
+        let call = idle_sessions.start_call(1);
+        idle_sessions.track(1, "alice", Some(0));
+        drop(call);
+        assert_eq!(idle_sessions.expired(), vec![(1, "alice".to_string())]);
+    }
+}
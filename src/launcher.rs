@@ -1,71 +1,147 @@
 /// Module for launching Spark sessions
 use std::{
     collections::HashMap,
-    env,
-    io::{self},
+    env, fs,
+    io::{self, Write},
+    os::unix::fs::{chown, DirBuilderExt, MetadataExt, OpenOptionsExt},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use log::info;
-use tokio::process::Command;
+use async_trait::async_trait;
+use log::{info, warn};
+use nix::unistd::{geteuid, User};
+use tokio::sync::broadcast;
+use uuid::Uuid;
 use which::which;
 
-use crate::config::{ProxyConfig, SparkVersion};
+use crate::{
+    cluster::{ClusterApp, ClusterManager},
+    config::{DeployMode, Impersonation, ProxyConfig, SparkVersion, TokenDelivery},
+    idle::IdleSessions,
+    logs::DriverLogs,
+    plugin,
+    policy::RejectedConfig,
+    store::{Session, SessionState, SessionStore},
+    supervisor::{DriverCommand, Supervisor},
+};
 
 static SPARK_HOME: &str = "SPARK_HOME";
-static TOKEN_CONFIG: &str = "spark.connect.proxy.token";
+static TOKEN_ENV: &str = "SPARK_CONNECT_PROXY_TOKEN";
+static TOKEN_FILE_CONFIG: &str = "spark.connect.proxy.tokenFile";
 static CALLBACK_CONFIG: &str = "spark.connect.proxy.callback";
 
+/// Starts and stops the drivers for sessions, and gives access to what they log
+#[async_trait]
+pub trait Launcher: Send + Sync {
+    fn get_versions(&self) -> Vec<String>;
+
+    /// Check user provided configs against the version's policy, returning the rejected
+    /// ones
+    fn check_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: &HashMap<String, String>,
+    ) -> Vec<RejectedConfig>;
+
+    /// The name of the version a session would use, and the configs its driver would run
+    /// with
+    fn effective_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: HashMap<String, String>,
+    ) -> Result<(String, HashMap<String, String>), io::Error>;
+
+    /// Launch the driver for a session with a new driver token, tracking its state until it
+    /// exits
+    async fn launch(
+        &self,
+        session_id: u64,
+        version_name: Option<&str>,
+        username: String,
+        user_config: HashMap<String, String>,
+    ) -> Result<(), io::Error>;
+
+    /// Stop the driver for a session, if it's running. Returns once the driver has exited.
+    async fn stop(&self, session_id: u64) -> Result<(), io::Error>;
+
+    /// The state of a session's application as reported by its cluster manager. `None` if
+    /// the session has no application or the state couldn't be understood.
+    async fn status(&self, session: &Session) -> Result<Option<String>, io::Error>;
+
+    /// The most recent lines logged by a session's driver
+    async fn logs(&self, session_id: u64, tail: Option<usize>) -> Result<Vec<String>, io::Error>;
+
+    /// Subscribe to new lines logged by a session's driver, if it's still running
+    fn follow_logs(&self, session_id: u64) -> Option<broadcast::Receiver<String>>;
+
+    async fn remove_logs(&self, session_id: u64);
+}
+
+/// Launches drivers by running spark-submit directly
 #[derive(Clone)]
-pub struct Launcher {
+pub struct SparkSubmitLauncher {
     // Map of Spark version key to path it's located at
     versions: Vec<SparkVersion>,
     callback_addr: String,
+    // Where token files are written for drivers that read their token from one
+    token_dir: PathBuf,
+    // Whether commands are run through a template, which may not pass the environment on
+    templated: bool,
+    session_store: Arc<dyn SessionStore>,
+    supervisor: Arc<Supervisor>,
+    driver_logs: Arc<DriverLogs>,
+    idle_sessions: Arc<IdleSessions>,
 }
 
-impl Launcher {
-    pub fn from_config(config: &ProxyConfig) -> Self {
-        let versions = config.spark_versions.clone();
+impl SparkSubmitLauncher {
+    pub fn from_config(
+        config: &ProxyConfig,
+        session_store: Arc<dyn SessionStore>,
+        driver_logs: Arc<DriverLogs>,
+        idle_sessions: Arc<IdleSessions>,
+    ) -> Self {
+        let mut versions = config.spark_versions.clone();
         let callback_addr = config.get_callback_addr();
+        let supervisor = Arc::new(Supervisor::new(
+            session_store.clone(),
+            driver_logs.clone(),
+            config.get_stop_grace_period(),
+        ));
+        idle_sessions.spawn_reaper(session_store.clone(), supervisor.clone());
 
         if versions.is_empty() {
-            // Check if SPARK_HOME is defined and use that as the default
-            if let Ok(home) = env::var(SPARK_HOME) {
-                let versions = vec![SparkVersion {
-                    name: "default".to_string(),
-                    home,
-                    default: true,
-                    ..Default::default()
-                }];
-                return Self {
-                    versions,
-                    callback_addr,
-                };
-            }
-
-            // Otherwise check if there is a `spark-submit` on the path and infer the home dir
-            if let Ok(submit_path) = which("spark-submit") {
-                let versions = vec![SparkVersion {
-                    name: "default".to_string(),
-                    home: submit_path
-                        .parent()
-                        .unwrap()
-                        .parent()
-                        .unwrap()
-                        .to_string_lossy()
-                        .to_string(),
-                    default: true,
-                    ..Default::default()
-                }];
-                return Self {
-                    versions,
-                    callback_addr,
-                };
-            }
-
-            panic!("Unable to find a default Spark installation")
+            versions.push(Self::default_version());
         }
 
+        // Traversable by the drivers' users, but not listable
+        let token_dir = match config.token_dir.as_ref() {
+            Some(dir) => {
+                let dir = PathBuf::from(dir);
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o711)
+                    .create(&dir)
+                    .unwrap_or_else(|e| {
+                        panic!("Failed to create token directory {:?}: {}", dir, e)
+                    });
+                check_private_dir(&dir, "Token");
+                dir
+            }
+            // A new directory every run, which nobody else can have made beforehand
+            None => {
+                let dir =
+                    env::temp_dir().join(format!("spark-connect-proxy-tokens-{}", Uuid::new_v4()));
+                fs::DirBuilder::new()
+                    .mode(0o711)
+                    .create(&dir)
+                    .unwrap_or_else(|e| {
+                        panic!("Failed to create token directory {:?}: {}", dir, e)
+                    });
+                dir
+            }
+        };
+
         // Check there is exactly one default
         assert_eq!(
             versions.iter().filter(|v| v.default).count(),
@@ -74,40 +150,230 @@ impl Launcher {
         );
 
         // Check all the Spark directories exist
-        for version in versions.iter() {
+        for version in versions.iter_mut() {
             assert!(
                 Path::new(&version.home).exists(),
                 "Home directory not found for version {}: {}",
                 version.name,
                 version.home
             );
+            assert!(
+                version.get_deploy_mode() == DeployMode::Client
+                    || ClusterManager::from_master(&version.get_master()) != ClusterManager::Local,
+                "Version {} can't use cluster deploy mode with a local master",
+                version.name
+            );
+
+            match version.impersonation.as_ref() {
+                Some(Impersonation::RunAs { helper: None }) => assert!(
+                    geteuid().is_root(),
+                    "Version {} runs drivers as their users, which needs the proxy to run as root or a helper",
+                    version.name
+                ),
+                Some(Impersonation::RunAs {
+                    helper: Some(helper),
+                }) => {
+                    assert!(
+                        !helper.is_empty(),
+                        "The run as helper for version {} can't be empty",
+                        version.name
+                    );
+                    // Token files are given to the driver's user, which only root can do
+                    assert!(
+                        token_delivery(version, false) == TokenDelivery::Env
+                            || geteuid().is_root(),
+                        "Version {} runs drivers through a helper without the proxy running as root, which needs token_delivery set to env and the helper to keep {}",
+                        version.name,
+                        TOKEN_ENV
+                    );
+                }
+                _ => (),
+            }
+
+            if let Some(policy) = version.config_policy.as_ref() {
+                for (key, rule) in policy.values.iter() {
+                    rule.validate(key);
+                }
+            }
+
+            let plugin_jar = plugin::resolve_jar(config, version);
+            info!(
+                "Using plugin jar {:?} for version {}",
+                plugin_jar, version.name
+            );
+            version.plugin_jar = Some(plugin_jar.to_string_lossy().to_string());
         }
 
         Self {
             versions,
             callback_addr,
+            token_dir,
+            templated: false,
+            session_store,
+            supervisor,
+            driver_logs,
+            idle_sessions,
         }
     }
 
-    pub fn get_versions(&self) -> Vec<String> {
-        self.versions.iter().map(|v| v.name.clone()).collect()
+    /// Settle the sessions whose driver was starting up or being stopped when the proxy
+    /// stopped, which nothing would supervise or time out. Starting drivers are stopped
+    /// and their sessions failed, and stopping ones are stopped for good.
+    pub async fn recover(&self) -> Result<(), io::Error> {
+        let sessions = self
+            .session_store
+            .list_unfinished_sessions()
+            .await
+            .map_err(io::Error::other)?;
+        for (_, session) in sessions {
+            let state = match session.state {
+                SessionState::Launching => SessionState::Failed {
+                    reason: "The proxy restarted while the driver was starting up".to_string(),
+                },
+                SessionState::Stopping => SessionState::Stopped,
+                _ => continue,
+            };
+            warn!(
+                "Session {} was {} before a restart, stopping its driver",
+                session.id,
+                session.state.name()
+            );
+            let cluster = self
+                .find_version(session.version.as_deref())
+                .ok()
+                .and_then(ClusterApp::for_version);
+            if let Err(e) = self
+                .supervisor
+                .stop_recovered(&session, cluster, state)
+                .await
+            {
+                warn!("Failed to stop driver of session {}: {}", session.id, e);
+            }
+        }
+        Ok(())
     }
 
-    pub async fn launch(
+    /// The version used when none are configured
+    fn default_version() -> SparkVersion {
+        // Check if SPARK_HOME is defined and use that as the default
+        if let Ok(home) = env::var(SPARK_HOME) {
+            return SparkVersion {
+                name: "default".to_string(),
+                home,
+                default: true,
+                ..Default::default()
+            };
+        }
+
+        // Otherwise check if there is a `spark-submit` on the path and infer the home dir
+        if let Ok(submit_path) = which("spark-submit") {
+            return SparkVersion {
+                name: "default".to_string(),
+                home: submit_path
+                    .parent()
+                    .unwrap()
+                    .parent()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string(),
+                default: true,
+                ..Default::default()
+            };
+        }
+
+        panic!("Unable to find a default Spark installation")
+    }
+
+    /// Launch the driver for a session, running the command `wrap` makes out of the
+    /// spark-submit command
+    async fn launch_with<F>(
         &self,
+        session_id: u64,
         version_name: Option<&str>,
         username: String,
-        token: String,
         user_config: HashMap<String, String>,
-    ) -> Result<(), io::Error> {
-        let version = if let Some(name) = version_name {
+        wrap: F,
+    ) -> Result<(), io::Error>
+    where
+        F: FnOnce(DriverCommand, &SparkVersion, &str) -> DriverCommand + Send,
+    {
+        match self
+            .session_store
+            .set_session_state(session_id, SessionState::Launching)
+            .await
+        {
+            Ok(true) => (),
+            // Deleted or being stopped in the meantime, so nothing would supervise the driver
+            Ok(false) => {
+                return Err(io::Error::other(format!(
+                    "Session {} can't be launched anymore",
+                    session_id
+                )))
+            }
+            Err(e) => return Err(io::Error::other(e)),
+        }
+
+        // Drivers never see the session's token, so it can be rotated while they run
+        let driver_token = Uuid::new_v4().to_string();
+        let result = match self
+            .session_store
+            .set_session_driver_token(session_id, driver_token.clone())
+            .await
+        {
+            Ok(()) => self
+                .driver_command(
+                    session_id,
+                    version_name,
+                    &username,
+                    driver_token,
+                    user_config,
+                )
+                .map(|(command, version)| (wrap(command, version, &username), version))
+                .and_then(|(command, version)| {
+                    self.supervisor.supervise(
+                        session_id,
+                        command,
+                        version.restart_policy.clone(),
+                    )?;
+                    // Only once there's a driver to stop when it goes idle
+                    self.idle_sessions
+                        .track(session_id, &username, version.idle_timeout);
+                    Ok(())
+                }),
+            Err(e) => Err(io::Error::other(e)),
+        };
+
+        if let Err(e) = result.as_ref() {
+            self.set_state(
+                session_id,
+                SessionState::Failed {
+                    reason: e.to_string(),
+                },
+            )
+            .await;
+        }
+        result
+    }
+
+    async fn set_state(&self, session_id: u64, state: SessionState) {
+        if let Err(e) = self
+            .session_store
+            .set_session_state(session_id, state)
+            .await
+        {
+            warn!("Failed to update state of session {}: {}", session_id, e);
+        }
+    }
+
+    fn find_version(&self, version_name: Option<&str>) -> Result<&SparkVersion, io::Error> {
+        if let Some(name) = version_name {
             self.versions
                 .iter()
                 .find(|v| v.name == name)
                 .ok_or(io::Error::new(
                     io::ErrorKind::NotFound,
                     format!("Version named {} not found", name),
-                ))?
+                ))
         } else {
             self.versions
                 .iter()
@@ -115,32 +381,102 @@ impl Launcher {
                 .ok_or(io::Error::new(
                     io::ErrorKind::NotFound,
                     "No default version found",
-                ))?
-        };
+                ))
+        }
+    }
 
-        // Start with the default config for this version
-        let mut configs = version.default_configs.clone().unwrap_or_default();
+    /// Write a driver's token to a file only the driver's user can read
+    fn write_token_file(
+        &self,
+        session_id: u64,
+        token: &str,
+        user: Option<&User>,
+    ) -> Result<PathBuf, io::Error> {
+        let path = self.token_dir.join(format!("session-{}.token", session_id));
+        // A file left from an earlier driver may belong to another user
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(token.as_bytes())?;
+        if let Some(user) = user {
+            chown(&path, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
+        }
+        Ok(path)
+    }
 
-        // Overwrite by user provided configs
-        configs.extend(user_config);
+    /// Build the spark-submit command for a session
+    fn driver_command(
+        &self,
+        session_id: u64,
+        version_name: Option<&str>,
+        username: &str,
+        token: String,
+        user_config: HashMap<String, String>,
+    ) -> Result<(DriverCommand, &SparkVersion), io::Error> {
+        let version = self.find_version(version_name)?;
 
-        // Merge any comma-separated configs
-        if let Some(merge_configs) = version.merge_configs.as_ref() {
-            for (key, value) in merge_configs.iter() {
-                if let Some(existing) = configs.get(key) {
-                    configs.insert(key.to_string(), format!("{},{}", existing, value));
-                }
+        if let Some(policy) = version.config_policy.as_ref() {
+            let rejected = policy.check(&user_config);
+            if !rejected.is_empty() {
+                let keys: Vec<&str> = rejected.iter().map(|r| r.key.as_str()).collect();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Configs rejected by policy: {}", keys.join(", ")),
+                ));
             }
         }
 
-        // Next overwrite by forced configs for this version
-        if let Some(override_configs) = version.override_configs.as_ref() {
-            configs.extend(override_configs.clone());
-        }
+        let mut configs = merge_configs(version, user_config);
 
-        // Finally add our internal configs
-        configs.insert(TOKEN_CONFIG.to_string(), token);
-        configs.insert(CALLBACK_CONFIG.to_string(), self.callback_addr.clone());
+        // Only drivers run as the user directly are spawned as them, but a helper's drivers
+        // need to be able to read their token file too
+        let (user, token_owner) = match version.impersonation.as_ref() {
+            Some(Impersonation::RunAs { helper: None }) => {
+                let user = local_user(username)?;
+                (Some(user.clone()), Some(user))
+            }
+            Some(Impersonation::RunAs { helper: Some(_) }) => (None, Some(local_user(username)?)),
+            _ => (None, None),
+        };
+
+        // Finally add our internal configs. The token is kept off the command line, where
+        // any local user could read it
+        let mut envs = version.env.clone().unwrap_or_default();
+        let mut token_file = None;
+        match token_delivery(version, self.templated) {
+            TokenDelivery::Env => {
+                envs.insert(TOKEN_ENV.to_string(), token);
+            }
+            TokenDelivery::File => {
+                let path = self.write_token_file(session_id, &token, token_owner.as_ref())?;
+                let path_str = path.to_string_lossy().to_string();
+                if version.get_deploy_mode() == DeployMode::Cluster {
+                    // Shipped to the driver's working directory, where it's found by name
+                    configs
+                        .entry("spark.files".to_string())
+                        .and_modify(|files| *files = format!("{},{}", files, path_str))
+                        .or_insert_with(|| path_str.clone());
+                    let name = path.file_name().unwrap().to_string_lossy().to_string();
+                    configs.insert(TOKEN_FILE_CONFIG.to_string(), name);
+                } else {
+                    configs.insert(TOKEN_FILE_CONFIG.to_string(), path_str);
+                }
+                token_file = Some(path);
+            }
+        }
+        configs.insert(
+            CALLBACK_CONFIG.to_string(),
+            version
+                .callback_address
+                .clone()
+                .unwrap_or_else(|| self.callback_addr.clone()),
+        );
         configs.insert(
             "spark.extraListeners".to_string(),
             "org.apache.spark.sql.connect.proxy.SparkConnectProxyListener".to_string(),
@@ -154,35 +490,598 @@ impl Launcher {
             "0".to_string(),
         );
 
+        let cluster = ClusterApp::for_version(version);
+        if let Some(cluster) = cluster.as_ref() {
+            cluster.add_configs(&mut configs);
+        }
+
         let submit_path = PathBuf::from(&version.home).join("bin/spark-submit");
 
-        let mut args = vec!["--master".to_string(), "local".to_string()];
+        let mut args = vec!["--master".to_string(), version.get_master()];
+        if version.get_deploy_mode() == DeployMode::Cluster {
+            args.extend(["--deploy-mode".to_string(), "cluster".to_string()]);
+        }
 
         for (key, value) in configs.iter() {
             args.extend(["--conf".to_string(), format!("{}={}", key, value)]);
         }
 
-        args.extend([
-            "--jars".to_string(),
-            "plugin/target/scala-2.13/spark-connect-proxy_2.13-0.1.0-SNAPSHOT.jar".to_string(),
-        ]);
+        if let Some(plugin_jar) = version.plugin_jar.as_ref() {
+            args.extend(["--jars".to_string(), plugin_jar.clone()]);
+        }
 
         args.extend([
             "--class".to_string(),
             "org.apache.spark.sql.connect.service.SparkConnectServer".to_string(),
         ]);
 
-        // args.extend(["--proxy-user".to_string(), username]);
+        let mut command = DriverCommand {
+            program: submit_path,
+            args,
+            envs,
+            cluster,
+            user,
+            token_file,
+        };
+
+        match version.impersonation.as_ref() {
+            Some(Impersonation::ProxyUser) => {
+                command
+                    .args
+                    .extend(["--proxy-user".to_string(), username.to_string()]);
+            }
+            Some(Impersonation::RunAs {
+                helper: Some(helper),
+            }) => {
+                let mut helper = helper.iter().map(|arg| arg.replace("{username}", username));
+                // Checked to not be empty at startup
+                let program = PathBuf::from(helper.next().unwrap());
+                let mut args: Vec<String> = helper.collect();
+                args.push(command.program.to_string_lossy().to_string());
+                args.append(&mut command.args);
+                command.program = program;
+                command.args = args;
+            }
+            Some(Impersonation::RunAs { helper: None }) | None => (),
+        }
 
-        info!("Running {:?} {}", submit_path, args.join(" "));
+        Ok((command, version))
+    }
+}
 
-        Command::new(submit_path)
-            .args(args)
-            .envs(version.env.clone().unwrap_or_default())
-            // .env("SPARK_HOME", &version.home)
-            // .stdout(Stdio::piped())
-            // .stderr(Stdio::piped())
-            .spawn()?;
-        Ok(())
+/// How a version's drivers are given their token. Only an explicitly configured `env` is
+/// trusted to reach drivers through a helper or template, which may clear the environment.
+fn token_delivery(version: &SparkVersion, templated: bool) -> TokenDelivery {
+    let wrapped = templated
+        || matches!(
+            version.impersonation,
+            Some(Impersonation::RunAs { helper: Some(_) })
+        );
+    version.token_delivery.unwrap_or(
+        if wrapped || version.get_deploy_mode() == DeployMode::Cluster {
+            TokenDelivery::File
+        } else {
+            TokenDelivery::Env
+        },
+    )
+}
+
+/// The configs a version's drivers run with given the user's, before the proxy's own are
+/// added
+fn merge_configs(
+    version: &SparkVersion,
+    user_config: HashMap<String, String>,
+) -> HashMap<String, String> {
+    // Start with the default config for this version
+    let mut configs = version.default_configs.clone().unwrap_or_default();
+
+    // Overwrite by user provided configs
+    configs.extend(user_config);
+
+    // Merge any comma-separated configs
+    if let Some(merge_configs) = version.merge_configs.as_ref() {
+        for (key, value) in merge_configs.iter() {
+            if let Some(existing) = configs.get(key) {
+                configs.insert(key.to_string(), format!("{},{}", existing, value));
+            }
+        }
+    }
+
+    // Next overwrite by forced configs for this version
+    if let Some(override_configs) = version.override_configs.as_ref() {
+        configs.extend(override_configs.clone());
+    }
+    configs
+}
+
+/// Check that only the proxy's user can add or replace files in a configured directory,
+/// described by `kind` in the panic if not
+pub fn check_private_dir(dir: &Path, kind: &str) {
+    let metadata = fs::symlink_metadata(dir)
+        .unwrap_or_else(|e| panic!("Failed to check {} directory {:?}: {}", kind, dir, e));
+    assert!(
+        metadata.is_dir(),
+        "{} directory {:?} isn't a directory",
+        kind,
+        dir
+    );
+    assert!(
+        metadata.uid() == geteuid().as_raw(),
+        "{} directory {:?} must be owned by the proxy's user",
+        kind,
+        dir
+    );
+    assert!(
+        metadata.mode() & 0o022 == 0,
+        "{} directory {:?} must not be writable by other users",
+        kind,
+        dir
+    );
+}
+
+/// The local account for a user, which drivers are never run as if it's root
+fn local_user(username: &str) -> Result<User, io::Error> {
+    let user = User::from_name(username)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No local account found for user {}", username),
+        )
+    })?;
+    if user.uid.is_root() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Drivers can't be run as root",
+        ));
+    }
+    Ok(user)
+}
+
+#[async_trait]
+impl Launcher for SparkSubmitLauncher {
+    fn get_versions(&self) -> Vec<String> {
+        self.versions.iter().map(|v| v.name.clone()).collect()
+    }
+
+    fn check_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: &HashMap<String, String>,
+    ) -> Vec<RejectedConfig> {
+        // An unknown version fails when launching instead
+        self.find_version(version_name)
+            .ok()
+            .and_then(|version| version.config_policy.as_ref())
+            .map(|policy| policy.check(user_config))
+            .unwrap_or_default()
+    }
+
+    fn effective_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: HashMap<String, String>,
+    ) -> Result<(String, HashMap<String, String>), io::Error> {
+        let version = self.find_version(version_name)?;
+        Ok((version.name.clone(), merge_configs(version, user_config)))
+    }
+
+    async fn launch(
+        &self,
+        session_id: u64,
+        version_name: Option<&str>,
+        username: String,
+        user_config: HashMap<String, String>,
+    ) -> Result<(), io::Error> {
+        self.launch_with(
+            session_id,
+            version_name,
+            username,
+            user_config,
+            |command, _, _| command,
+        )
+        .await
+    }
+
+    async fn stop(&self, session_id: u64) -> Result<(), io::Error> {
+        if !self.supervisor.is_supervised(session_id) {
+            // Drivers that outlived a restart of the proxy aren't supervised anymore
+            let session = self
+                .session_store
+                .get_session_by_id(session_id)
+                .await
+                .map_err(io::Error::other)?;
+            if let Some(session) = session.filter(|session| {
+                !matches!(
+                    session.state,
+                    SessionState::Stopped | SessionState::Failed { .. }
+                ) && (session.driver_process.is_some() || session.app_id.is_some())
+            }) {
+                let cluster = self
+                    .find_version(session.version.as_deref())
+                    .ok()
+                    .and_then(ClusterApp::for_version);
+                return self
+                    .supervisor
+                    .stop_recovered(&session, cluster, SessionState::Stopped)
+                    .await;
+            }
+        }
+        self.supervisor.stop(session_id).await
+    }
+
+    async fn status(&self, session: &Session) -> Result<Option<String>, io::Error> {
+        let Some(app_id) = session.app_id.as_ref() else {
+            return Ok(None);
+        };
+        let cluster = self
+            .find_version(session.version.as_deref())
+            .ok()
+            .and_then(ClusterApp::for_version);
+        match cluster {
+            Some(cluster) => cluster.status(app_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn logs(&self, session_id: u64, tail: Option<usize>) -> Result<Vec<String>, io::Error> {
+        self.driver_logs.tail(session_id, tail).await
+    }
+
+    fn follow_logs(&self, session_id: u64) -> Option<broadcast::Receiver<String>> {
+        self.driver_logs.subscribe(session_id)
+    }
+
+    async fn remove_logs(&self, session_id: u64) {
+        self.driver_logs.remove(session_id).await
+    }
+}
+
+/// Launches drivers by running a configured command template instead of spark-submit
+/// itself, for wrapping it in srun, sudo, systemd-run, docker run and the like. Drivers
+/// are otherwise handled like spark-submit ones.
+pub struct CommandTemplateLauncher {
+    spark_submit: SparkSubmitLauncher,
+    // Program followed by its arguments, with placeholders
+    template: Vec<String>,
+}
+
+impl CommandTemplateLauncher {
+    pub fn new(mut spark_submit: SparkSubmitLauncher, template: Vec<String>) -> Self {
+        assert!(!template.is_empty(), "The command template can't be empty");
+        spark_submit.templated = true;
+        Self {
+            spark_submit,
+            template,
+        }
+    }
+
+    /// Fill in the template for a spark-submit command. An argument that is exactly
+    /// `{args}` expands to all of spark-submit's arguments, and `{spark_submit}`,
+    /// `{spark_home}`, `{version}`, `{session_id}`, `{username}` and `{token_file}` are
+    /// replaced anywhere. `{token_file}` is empty for drivers given their token by env.
+    fn render(
+        &self,
+        session_id: u64,
+        command: DriverCommand,
+        version: &SparkVersion,
+        username: &str,
+    ) -> DriverCommand {
+        let token_file = command
+            .token_file
+            .as_ref()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let substitute = |arg: &str| {
+            arg.replace("{spark_submit}", &command.program.to_string_lossy())
+                .replace("{spark_home}", &version.home)
+                .replace("{version}", &version.name)
+                .replace("{session_id}", &session_id.to_string())
+                .replace("{username}", username)
+                .replace("{token_file}", &token_file)
+        };
+
+        let mut args = Vec::new();
+        for arg in self.template[1..].iter() {
+            if arg == "{args}" {
+                args.extend(command.args.iter().cloned());
+            } else {
+                args.push(substitute(arg));
+            }
+        }
+
+        DriverCommand {
+            program: PathBuf::from(substitute(&self.template[0])),
+            args,
+            envs: command.envs,
+            cluster: command.cluster,
+            user: command.user,
+            token_file: command.token_file,
+        }
+    }
+}
+
+#[async_trait]
+impl Launcher for CommandTemplateLauncher {
+    fn get_versions(&self) -> Vec<String> {
+        self.spark_submit.get_versions()
+    }
+
+    fn check_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: &HashMap<String, String>,
+    ) -> Vec<RejectedConfig> {
+        self.spark_submit.check_config(version_name, user_config)
+    }
+
+    fn effective_config(
+        &self,
+        version_name: Option<&str>,
+        user_config: HashMap<String, String>,
+    ) -> Result<(String, HashMap<String, String>), io::Error> {
+        self.spark_submit
+            .effective_config(version_name, user_config)
+    }
+
+    async fn launch(
+        &self,
+        session_id: u64,
+        version_name: Option<&str>,
+        username: String,
+        user_config: HashMap<String, String>,
+    ) -> Result<(), io::Error> {
+        self.spark_submit
+            .launch_with(
+                session_id,
+                version_name,
+                username,
+                user_config,
+                |command, version, username| self.render(session_id, command, version, username),
+            )
+            .await
+    }
+
+    async fn stop(&self, session_id: u64) -> Result<(), io::Error> {
+        self.spark_submit.stop(session_id).await
+    }
+
+    async fn status(&self, session: &Session) -> Result<Option<String>, io::Error> {
+        self.spark_submit.status(session).await
+    }
+
+    async fn logs(&self, session_id: u64, tail: Option<usize>) -> Result<Vec<String>, io::Error> {
+        self.spark_submit.logs(session_id, tail).await
+    }
+
+    fn follow_logs(&self, session_id: u64) -> Option<broadcast::Receiver<String>> {
+        self.spark_submit.follow_logs(session_id)
+    }
+
+    async fn remove_logs(&self, session_id: u64) {
+        self.spark_submit.remove_logs(session_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use super::*;
+    use crate::store::InMemorySessionStore;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir().join(format!("spark-connect-proxy-test-{}", Uuid::new_v4()))
+    }
+
+    fn version(impersonation: Option<Impersonation>) -> SparkVersion {
+        SparkVersion {
+            name: "test".to_string(),
+            home: env::temp_dir().to_string_lossy().to_string(),
+            default: true,
+            impersonation,
+            ..Default::default()
+        }
+    }
+
+    fn spark_submit(version: SparkVersion, token_dir: &Path) -> SparkSubmitLauncher {
+        fs::DirBuilder::new().mode(0o711).create(token_dir).unwrap();
+        let plugin_jar = token_dir.join("plugin.jar");
+        fs::write(&plugin_jar, "").unwrap();
+        let config = ProxyConfig {
+            spark_versions: vec![SparkVersion {
+                plugin_jar: Some(plugin_jar.to_string_lossy().to_string()),
+                ..version
+            }],
+            token_dir: Some(token_dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        SparkSubmitLauncher::from_config(
+            &config,
+            Arc::new(InMemorySessionStore::default()),
+            Arc::new(DriverLogs::from_config(&config)),
+            Arc::new(IdleSessions::from_config(&config)),
+        )
+    }
+
+    #[test]
+    fn delivers_tokens_by_file_through_helpers_and_templates() {
+        let helper = Some(Impersonation::RunAs {
+            helper: Some(vec!["sudo".to_string()]),
+        });
+        assert_eq!(token_delivery(&version(None), false), TokenDelivery::Env);
+        assert_eq!(token_delivery(&version(None), true), TokenDelivery::File);
+        assert_eq!(
+            token_delivery(&version(helper.clone()), false),
+            TokenDelivery::File
+        );
+
+        // Unless env is asked for
+        let explicit = SparkVersion {
+            token_delivery: Some(TokenDelivery::Env),
+            ..version(helper)
+        };
+        assert_eq!(token_delivery(&explicit, true), TokenDelivery::Env);
+    }
+
+    #[tokio::test]
+    async fn doesnt_launch_sessions_being_stopped() {
+        let token_dir = temp_path();
+        let home = temp_path();
+        fs::create_dir_all(home.join("bin")).unwrap();
+        let submit = home.join("bin/spark-submit");
+        fs::write(&submit, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        fs::set_permissions(&submit, fs::Permissions::from_mode(0o755)).unwrap();
+        let launcher = spark_submit(
+            SparkVersion {
+                home: home.to_string_lossy().to_string(),
+                ..version(None)
+            },
+            &token_dir,
+        );
+
+        let session = launcher
+            .session_store
+            .create_session("alice", "token".to_string(), None, None, HashMap::new())
+            .await
+            .unwrap();
+        launcher
+            .session_store
+            .set_session_state(session.id, SessionState::Stopping)
+            .await
+            .unwrap();
+        assert!(launcher
+            .launch(session.id, None, "alice".to_string(), HashMap::new())
+            .await
+            .is_err());
+        assert!(!launcher.supervisor.is_supervised(session.id));
+        let session = launcher
+            .session_store
+            .get_session_by_id(session.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.state, SessionState::Stopping);
+
+        fs::remove_dir_all(&token_dir).unwrap();
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[tokio::test]
+    async fn doesnt_track_sessions_whose_driver_failed_to_start() {
+        let token_dir = temp_path();
+        let launcher = spark_submit(
+            SparkVersion {
+                idle_timeout: Some(60),
+                ..version(None)
+            },
+            &token_dir,
+        );
+        let session = launcher
+            .session_store
+            .create_session("alice", "token".to_string(), None, None, HashMap::new())
+            .await
+            .unwrap();
+
+        // There's no spark-submit in the version's home
+        assert!(launcher
+            .launch(session.id, None, "alice".to_string(), HashMap::new())
+            .await
+            .is_err());
+        assert!(!launcher.idle_sessions.is_tracked(session.id));
+        fs::remove_dir_all(&token_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_sessions_that_were_launching_before_a_restart() {
+        let token_dir = temp_path();
+        let launcher = spark_submit(version(None), &token_dir);
+        let session_store = &launcher.session_store;
+        let mut ids = Vec::new();
+        for states in [
+            vec![SessionState::Launching],
+            vec![SessionState::Launching, SessionState::Ready],
+            vec![SessionState::Launching, SessionState::Stopping],
+        ] {
+            let session = session_store
+                .create_session(
+                    "alice",
+                    Uuid::new_v4().to_string(),
+                    None,
+                    None,
+                    HashMap::new(),
+                )
+                .await
+                .unwrap();
+            for state in states {
+                session_store
+                    .set_session_state(session.id, state)
+                    .await
+                    .unwrap();
+            }
+            ids.push(session.id);
+        }
+
+        launcher.recover().await.unwrap();
+        let mut states = Vec::new();
+        for id in ids {
+            let session = session_store.get_session_by_id(id).await.unwrap().unwrap();
+            states.push(session.state.name());
+        }
+        assert_eq!(states, ["failed", "ready", "stopped"]);
+        fs::remove_dir_all(&token_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn templates_are_given_the_token_file() {
+        let token_dir = temp_path();
+        let launcher = CommandTemplateLauncher::new(
+            spark_submit(version(None), &token_dir),
+            ["docker", "run", "-v", "{token_file}:/token", "{args}"]
+                .map(String::from)
+                .to_vec(),
+        );
+
+        let (command, version) = launcher
+            .spark_submit
+            .driver_command(1, None, "alice", "secret".to_string(), HashMap::new())
+            .unwrap();
+        let command = launcher.render(1, command, version, "alice");
+
+        let token_file = token_dir.join("session-1.token");
+        assert_eq!(fs::read_to_string(&token_file).unwrap(), "secret");
+        assert_eq!(command.args[2], format!("{}:/token", token_file.display()));
+        assert!(command
+            .args
+            .contains(&format!("{}={}", TOKEN_FILE_CONFIG, token_file.display())));
+        assert!(!command.envs.contains_key(TOKEN_ENV));
+        fs::remove_dir_all(&token_dir).unwrap();
+    }
+
+    #[test]
+    fn accepts_private_token_dirs() {
+        let dir = temp_path();
+        fs::DirBuilder::new().mode(0o711).create(&dir).unwrap();
+        check_private_dir(&dir, "Token");
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be writable by other users")]
+    fn rejects_world_writable_token_dirs() {
+        let dir = temp_path();
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        let result = std::panic::catch_unwind(|| check_private_dir(&dir, "Token"));
+        fs::remove_dir(&dir).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a directory")]
+    fn rejects_symlinked_token_dirs() {
+        let link = temp_path();
+        symlink(env::temp_dir(), &link).unwrap();
+        let result = std::panic::catch_unwind(|| check_private_dir(&link, "Token"));
+        fs::remove_file(&link).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
     }
 }
@@ -0,0 +1,328 @@
+/// Module for capturing and serving driver logs
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::Permissions,
+    io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::warn;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    process::Child,
+    sync::{broadcast, mpsc},
+};
+
+use crate::config::ProxyConfig;
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+// Lines buffered for each follower before it starts missing lines
+const FOLLOW_BUFFER: usize = 1024;
+
+/// Captures the stdout and stderr of driver processes into per-session rotating log files
+pub struct DriverLogs {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    // Live log lines for sessions whose driver is supervised
+    live: Mutex<HashMap<u64, broadcast::Sender<String>>>,
+}
+
+impl DriverLogs {
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        let dir = config
+            .driver_logs
+            .dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("spark-connect-proxy-logs"));
+
+        // Driver output can include secrets, so only the proxy's user may read it
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .and_then(|_| std::fs::set_permissions(&dir, Permissions::from_mode(0o700)))
+            .unwrap_or_else(|e| panic!("Failed to create driver log directory {:?}: {}", dir, e));
+
+        Self {
+            dir,
+            max_bytes: config.driver_logs.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_files: config.driver_logs.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            live: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, session_id: u64) -> PathBuf {
+        self.dir.join(format!("session-{}.log", session_id))
+    }
+
+    /// Every log file for a session, oldest first
+    fn paths(&self, session_id: u64) -> Vec<PathBuf> {
+        let path = self.path(session_id);
+        let mut paths: Vec<PathBuf> = (1..=self.max_files)
+            .rev()
+            .map(|i| rotated_path(&path, i))
+            .collect();
+        paths.push(path);
+        paths
+    }
+
+    /// Start capturing the output of a driver process. The child must have been spawned
+    /// with piped stdout and stderr. Returns a receiver for the captured lines. Logs left
+    /// by an earlier driver with the same session ID are removed first if `fresh`, which
+    /// isn't the case when a supervised driver is restarted.
+    pub fn capture(
+        &self,
+        session_id: u64,
+        child: &mut Child,
+        fresh: bool,
+    ) -> broadcast::Receiver<String> {
+        let live = self
+            .live
+            .lock()
+            .unwrap()
+            .entry(session_id)
+            .or_insert_with(|| broadcast::channel(FOLLOW_BUFFER).0)
+            .clone();
+        // Subscribed before anything is read so the caller sees every line
+        let lines = live.subscribe();
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        if let Some(stdout) = child.stdout.take() {
+            tokio::task::spawn(read_lines(stdout, tx.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::task::spawn(read_lines(stderr, tx));
+        }
+
+        let writer = LogWriter {
+            path: self.path(session_id),
+            stale: if fresh {
+                self.paths(session_id)
+            } else {
+                Vec::new()
+            },
+            file: None,
+            size: 0,
+            max_bytes: self.max_bytes,
+            max_files: self.max_files,
+        };
+        tokio::task::spawn(writer.run(rx, live));
+        lines
+    }
+
+    /// Stop offering live lines for a session, ending any follow streams once the
+    /// remaining output has been written
+    pub fn finish(&self, session_id: u64) {
+        self.live.lock().unwrap().remove(&session_id);
+    }
+
+    /// Subscribe to new lines for a session, if its driver is still running
+    pub fn subscribe(&self, session_id: u64) -> Option<broadcast::Receiver<String>> {
+        self.live
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .map(|live| live.subscribe())
+    }
+
+    /// Read the last `lines` lines logged for a session, or everything if `None`
+    pub async fn tail(&self, session_id: u64, lines: Option<usize>) -> io::Result<Vec<String>> {
+        let mut tail = VecDeque::new();
+        for path in self.paths(session_id) {
+            let contents = match fs::read(&path).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in String::from_utf8_lossy(&contents).lines() {
+                tail.push_back(line.to_string());
+                if lines.is_some_and(|lines| tail.len() > lines) {
+                    tail.pop_front();
+                }
+            }
+        }
+        Ok(tail.into())
+    }
+
+    /// Delete all logs for a session
+    pub async fn remove(&self, session_id: u64) {
+        remove_logs(&self.paths(session_id)).await
+    }
+}
+
+async fn remove_logs(paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove driver log {:?}: {}", path, e)
+            }
+            _ => (),
+        }
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    path.into()
+}
+
+async fn read_lines(output: impl AsyncRead + Unpin, tx: mpsc::UnboundedSender<String>) {
+    let mut reader = BufReader::new(output);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                if tx
+                    .send(line.trim_end_matches(['\r', '\n']).to_string())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to read driver output: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Appends lines to a log file, rotating it once it grows past `max_bytes`
+struct LogWriter {
+    path: PathBuf,
+    // Logs of an earlier driver to remove before anything is written
+    stale: Vec<PathBuf>,
+    file: Option<File>,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl LogWriter {
+    async fn run(
+        mut self,
+        mut rx: mpsc::UnboundedReceiver<String>,
+        live: broadcast::Sender<String>,
+    ) {
+        remove_logs(&self.stale).await;
+        while let Some(line) = rx.recv().await {
+            if let Err(e) = self.write_line(&line).await {
+                warn!("Failed to write driver log {:?}: {}", self.path, e);
+            }
+            // Nobody following is fine
+            let _ = live.send(line);
+        }
+    }
+
+    async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.file.is_some() && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate().await?;
+        }
+
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(&self.path)
+                    .await?;
+                self.size = file.metadata().await?.len();
+                self.file.insert(file)
+            }
+        };
+
+        file.write_all(line.as_bytes()).await?;
+        file.write_all(b"\n").await?;
+        self.size += len;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path).await;
+        }
+        for i in (1..self.max_files).rev() {
+            match fs::rename(rotated_path(&self.path, i), rotated_path(&self.path, i + 1)).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Stdio, time::Duration};
+
+    use tokio::process::Command;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn driver_logs() -> DriverLogs {
+        let mut config = ProxyConfig::default();
+        config.driver_logs.dir = Some(
+            std::env::temp_dir()
+                .join(format!("spark-connect-proxy-logs-{}", Uuid::new_v4()))
+                .to_string_lossy()
+                .to_string(),
+        );
+        DriverLogs::from_config(&config)
+    }
+
+    async fn run(logs: &DriverLogs, line: &str, fresh: bool) {
+        let mut child = Command::new("echo")
+            .arg(line)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = logs.capture(1, &mut child, fresh);
+        // The line is only sent once it's been written
+        tokio::time::timeout(Duration::from_secs(5), lines.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        child.wait().await.unwrap();
+        logs.finish(1);
+    }
+
+    #[tokio::test]
+    async fn removes_stale_logs_of_new_drivers_only() {
+        let logs = driver_logs();
+        std::fs::write(logs.path(1), "stale\n").unwrap();
+        std::fs::write(rotated_path(&logs.path(1), 1), "rotated\n").unwrap();
+
+        run(&logs, "first", true).await;
+        assert_eq!(logs.tail(1, None).await.unwrap(), vec!["first"]);
+        assert!(!rotated_path(&logs.path(1), 1).exists());
+
+        // A restarted driver keeps the logs of the one before it
+        run(&logs, "restarted", false).await;
+        assert_eq!(
+            logs.tail(1, None).await.unwrap(),
+            vec!["first", "restarted"]
+        );
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&logs.dir), 0o700);
+        assert_eq!(mode(&logs.path(1)), 0o600);
+
+        std::fs::remove_dir_all(&logs.dir).unwrap();
+    }
+}
@@ -1,33 +1,41 @@
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::{fs, io};
 
-use axum::Router;
-use clap::{command, Parser};
-use config::ProxyConfig;
-use http::header::AUTHORIZATION;
-use http::{response, status, StatusCode};
-use hyper::body::Incoming;
-use hyper::service::Service;
-use hyper::{Request, Response};
-
+use auth::ClientCertificate;
+use clap::Parser;
+use config::{ProxyConfig, SessionStoreConfig};
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto::Builder;
-use log::info;
+use idle::IdleSessions;
+use log::{info, warn};
+use proxy::{ProxyContext, ProxyService};
 use routes::get_router;
+use rustls::server::WebPkiClientVerifier;
 use rustls_pemfile::{certs, private_key};
-use store::{InMemorySessionStore, SessionStore};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, oneshot};
+use store::{InMemorySessionStore, SessionStore, SqliteSessionStore};
 use tokio_rustls::TlsAcceptor;
-use tower::Service as TowerService;
+use upstream::UpstreamPool;
 
 mod auth;
+mod cluster;
 mod config;
+#[cfg(test)]
+mod fake_driver;
+#[cfg(test)]
+mod fake_launcher;
+mod grpc;
+mod idle;
 mod launcher;
+mod logs;
+mod plugin;
+mod policy;
+mod proxy;
+mod quota;
 mod routes;
 mod store;
+mod supervisor;
+mod upstream;
+mod warm;
 
 /// Start the Spark Connect Proxy server
 #[derive(Parser, Debug)]
@@ -39,7 +47,7 @@ struct Args {
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     env_logger::init();
 
     let args = Args::parse();
@@ -56,24 +64,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", bind_host, bind_port)).await?;
     println!("Listening on http://{:?}", listener.local_addr().unwrap());
 
-    let session_store = Arc::new(InMemorySessionStore::default());
-    let router = get_router(&config, session_store.clone());
+    let session_store: Arc<dyn SessionStore> = match &config.session_store {
+        SessionStoreConfig::Memory => Arc::new(InMemorySessionStore::default()),
+        SessionStoreConfig::Sqlite { path } => {
+            let store = SqliteSessionStore::open(path)?;
+            store.recover().await?;
+            Arc::new(store)
+        }
+    };
+    let idle_sessions = Arc::new(IdleSessions::from_config(&config));
+    let upstreams = Arc::new(UpstreamPool::new(idle_sessions.clone()));
+    let router = get_router(
+        &config,
+        session_store.clone(),
+        idle_sessions,
+        upstreams.clone(),
+    )
+    .await;
     let tls_acceptor = load_tls_acceptor(&config)?;
+    let proxy_context = Arc::new(ProxyContext::from_config(&config, session_store, upstreams));
 
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
 
         info!("Serving new connection");
         let router = router.clone();
-        let session_store = session_store.clone();
-
-        if let Some(acceptor) = tls_acceptor.as_ref() {
-            let io = TokioIo::new(acceptor.accept(stream).await?);
+        let proxy_context = proxy_context.clone();
 
+        if let Some(acceptor) = tls_acceptor.clone() {
             tokio::task::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("TLS handshake failed: {:?}", err);
+                        return;
+                    }
+                };
+
+                let client_cert = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .map(|cert| ClientCertificate(cert.clone().into_owned()));
+
                 // Serve via TLS
                 let result = Builder::new(TokioExecutor::new())
-                    .serve_connection(io, ProxyService::new(router, session_store))
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        ProxyService::new(router, proxy_context, client_cert),
+                    )
                     .await;
 
                 if let Err(err) = result {
@@ -86,7 +132,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let result = Builder::new(TokioExecutor::new())
                     .serve_connection(
                         TokioIo::new(stream),
-                        ProxyService::new(router, session_store),
+                        ProxyService::new(router, proxy_context, None),
                     )
                     .await;
 
@@ -104,8 +150,27 @@ fn load_tls_acceptor(config: &ProxyConfig) -> Result<Option<TlsAcceptor>, io::Er
             .collect::<io::Result<Vec<_>>>()?;
         let key = private_key(&mut io::BufReader::new(fs::File::open(&tls_config.key)?))?.unwrap();
 
-        let mut config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
+        let builder = rustls::ServerConfig::builder();
+        let builder = if let Some(client_ca) = &tls_config.client_ca {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut io::BufReader::new(fs::File::open(client_ca)?)) {
+                roots
+                    .add(cert?)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            }
+
+            // Spark callbacks don't present a certificate, so it's optional
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).allow_unauthenticated();
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+            )
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let mut config = builder
             .with_single_cert(certs, key)
             .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
 
@@ -117,148 +182,3 @@ fn load_tls_acceptor(config: &ProxyConfig) -> Result<Option<TlsAcceptor>, io::Er
         Ok(None)
     }
 }
-
-type UpstreamMessage = (
-    Request<Incoming>,
-    oneshot::Sender<Result<Response<axum::body::Body>, hyper::Error>>,
-);
-
-struct UpstreamConnection {
-    rx: mpsc::UnboundedReceiver<UpstreamMessage>,
-}
-
-impl UpstreamConnection {
-    async fn start(mut self, addr: &str) {
-        let client_stream = TcpStream::connect(addr).await.unwrap();
-        let io = TokioIo::new(client_stream);
-
-        let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), io)
-            .await
-            .unwrap();
-        tokio::task::spawn(async move {
-            println!("Spawned connection await");
-            if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
-            }
-        });
-
-        loop {
-            if let Some((mut req, tx)) = self.rx.recv().await {
-                let uri_string = format!(
-                    "http://{}{}",
-                    addr,
-                    req.uri()
-                        .path_and_query()
-                        .map(|x| x.as_str())
-                        .unwrap_or("/")
-                );
-                *req.uri_mut() = uri_string.parse().unwrap();
-
-                println!("Proxying request {:?}", req.uri().path_and_query());
-
-                tx.send(
-                    sender
-                        .send_request(req)
-                        .await
-                        .map(|response| response.map(axum::body::Body::new)),
-                )
-                .unwrap();
-            } else {
-                println!("Connection closed, exiting loop");
-                break;
-            }
-        }
-    }
-}
-
-struct ProxyService {
-    dispatch: Mutex<Option<mpsc::UnboundedSender<UpstreamMessage>>>,
-    router: Router,
-    session_store: Arc<dyn SessionStore>,
-}
-
-impl ProxyService {
-    fn new(router: Router, session_store: Arc<dyn SessionStore>) -> Self {
-        Self {
-            dispatch: Mutex::new(None),
-            router,
-            session_store,
-        }
-    }
-
-    fn dispatch(
-        &self,
-        req: Request<Incoming>,
-    ) -> oneshot::Receiver<Result<Response<axum::body::Body>, hyper::Error>> {
-        let mut dispatch = self.dispatch.lock().unwrap();
-        let (tx, rx) = oneshot::channel();
-        if dispatch.is_none() {
-            let authorization = if let Some(auth) = req.headers().get(AUTHORIZATION) {
-                auth.to_str().unwrap().to_string()
-            } else {
-                let response = Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(().into())
-                    .unwrap();
-                tx.send(Ok(response)).unwrap();
-                return rx;
-            };
-
-            let split = authorization.split_once(' ');
-            let token = match split {
-                Some(("Bearer", token)) => token,
-                _ => {
-                    let response = Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(().into())
-                        .unwrap();
-                    tx.send(Ok(response)).unwrap();
-                    return rx;
-                }
-            };
-
-            if let Some(session) = self.session_store.get_session_by_token(token) {
-                let (upstream_sender, upstream_receiver) = mpsc::unbounded_channel();
-                let upstream = UpstreamConnection {
-                    rx: upstream_receiver,
-                };
-                tokio::task::spawn(
-                    async move { upstream.start(session.addr.unwrap().as_ref()).await },
-                );
-                *dispatch = Some(upstream_sender);
-            } else {
-                let response = Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(().into())
-                    .unwrap();
-                tx.send(Ok(response)).unwrap();
-                return rx;
-            }
-        }
-        dispatch.as_mut().unwrap().send((req, tx)).unwrap();
-        rx
-    }
-}
-
-impl Service<Request<hyper::body::Incoming>> for ProxyService {
-    type Response = Response<axum::body::Body>;
-
-    type Error = hyper::Error;
-
-    type Future =
-        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
-
-    fn call(&self, req: Request<hyper::body::Incoming>) -> Self::Future {
-        if req
-            .uri()
-            .path()
-            .starts_with("/spark.connect.SparkConnectService")
-        {
-            let rx = self.dispatch(req);
-            Box::pin(async { Ok(rx.await.unwrap()?.map(axum::body::Body::new)) })
-        } else {
-            let mut router = self.router.clone();
-            Box::pin(async move { Ok(router.call(req).await.unwrap()) })
-        }
-    }
-}
@@ -0,0 +1,161 @@
+/// Module for finding the plugin jar each Spark version's drivers run with
+use std::{
+    env, fs,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use log::info;
+use uuid::Uuid;
+
+use crate::{
+    config::{ProxyConfig, SparkVersion},
+    launcher::check_private_dir,
+};
+
+// Version of the plugin built by sbt, as set in build.sbt
+const PLUGIN_VERSION: &str = "0.1.0-SNAPSHOT";
+// Scala version of the plugin built by sbt. Spark 4, which it's built against, is only
+// published for Scala 2.13. Also assumed when it can't be detected from a Spark home.
+const SCALA_VERSION: &str = "2.13";
+
+// Jar built into the proxy binary with the `bundle-plugin-2_13` feature
+#[cfg(feature = "bundle-plugin-2_13")]
+const BUNDLED: Option<&[u8]> = Some(include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/plugin/target/scala-2.13/spark-connect-proxy_2.13-0.1.0-SNAPSHOT.jar"
+)));
+#[cfg(not(feature = "bundle-plugin-2_13"))]
+const BUNDLED: Option<&[u8]> = None;
+
+fn jar_name(scala_version: &str) -> String {
+    format!(
+        "spark-connect-proxy_{}-{}.jar",
+        scala_version, PLUGIN_VERSION
+    )
+}
+
+/// The Scala version a Spark installation was built with, from its scala-library jar
+fn detect_scala_version(home: &str) -> Option<String> {
+    fs::read_dir(Path::new(home).join("jars"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let version = name.strip_prefix("scala-library-")?;
+            let mut parts = version.split('.');
+            Some(format!("{}.{}", parts.next()?, parts.next()?))
+        })
+}
+
+/// Find the absolute path of the plugin jar for a version, panicking if there isn't one.
+/// A jar configured for the version is used as is. Otherwise, if the version uses the
+/// Scala version the plugin is built for, a jar is looked for in `plugin_dir`, then in
+/// the sbt output under the working directory, and finally a bundled jar is extracted
+/// into `plugin_dir`, or a new temp directory if it isn't set.
+pub fn resolve_jar(config: &ProxyConfig, version: &SparkVersion) -> PathBuf {
+    if let Some(jar) = version.plugin_jar.as_ref() {
+        let jar = Path::new(jar);
+        assert!(
+            jar.is_file(),
+            "Plugin jar not found for version {}: {:?}",
+            version.name,
+            jar
+        );
+        return absolute(jar);
+    }
+
+    let scala_version = version
+        .scala_version
+        .clone()
+        .or_else(|| detect_scala_version(&version.home))
+        .unwrap_or_else(|| SCALA_VERSION.to_string());
+    assert!(
+        scala_version == SCALA_VERSION,
+        "Version {} uses Scala {}, but the plugin is only built for Scala {}. Set plugin_jar \
+        to a jar built for it.",
+        version.name,
+        scala_version,
+        SCALA_VERSION
+    );
+    let name = jar_name(&scala_version);
+
+    let plugin_dir = config.plugin_dir.as_ref().map(PathBuf::from);
+    let mut candidates = Vec::new();
+    if let Some(dir) = plugin_dir.as_ref() {
+        candidates.push(dir.join(&name));
+    }
+    candidates.push(
+        Path::new("plugin/target")
+            .join(format!("scala-{}", scala_version))
+            .join(&name),
+    );
+    if let Some(jar) = candidates.iter().find(|jar| jar.is_file()) {
+        return absolute(jar);
+    }
+
+    let Some(bundled) = BUNDLED else {
+        panic!(
+            "No plugin jar found for version {} using Scala {}, looked for {:?}",
+            version.name, scala_version, candidates
+        );
+    };
+    // Drivers run whatever jar is here, so nobody else may be able to replace it
+    let dir = match plugin_dir {
+        Some(dir) => {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(&dir)
+                .unwrap_or_else(|e| panic!("Failed to create plugin directory {:?}: {}", dir, e));
+            check_private_dir(&dir, "Plugin");
+            dir
+        }
+        None => extract_dir().clone(),
+    };
+    let jar = dir.join(&name);
+    if fs::read(&jar).ok().as_deref() != Some(bundled) {
+        info!("Extracting bundled plugin jar to {:?}", jar);
+        fs::write(&jar, bundled)
+            .unwrap_or_else(|e| panic!("Failed to extract plugin jar to {:?}: {}", jar, e));
+    }
+    absolute(&jar)
+}
+
+/// A new directory for extracting bundled jars into, made once per run so nobody else can
+/// have made it beforehand. Readable by the drivers' users.
+fn extract_dir() -> &'static PathBuf {
+    static EXTRACT_DIR: OnceLock<PathBuf> = OnceLock::new();
+    EXTRACT_DIR.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("spark-connect-proxy-plugin-{}", Uuid::new_v4()));
+        fs::DirBuilder::new()
+            .mode(0o755)
+            .create(&dir)
+            .unwrap_or_else(|e| panic!("Failed to create plugin directory {:?}: {}", dir, e));
+        dir
+    })
+}
+
+// Drivers may run from a different directory, or a different host entirely
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .unwrap_or_else(|e| panic!("Failed to resolve plugin jar {:?}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn extracts_into_one_private_directory_per_run() {
+        let dir = extract_dir();
+        assert_eq!(extract_dir(), dir);
+        check_private_dir(dir, "Plugin");
+        let mode = fs::metadata(dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+/// Module for checking user provided configs against a version's policy
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::config::{ConfigPolicy, ValueRule};
+
+/// A config key a user isn't allowed to set, or to set to the value they gave
+#[derive(Clone, Debug, Serialize)]
+pub struct RejectedConfig {
+    pub key: String,
+    pub reason: String,
+}
+
+/// Whether a key matches a policy pattern. Patterns ending in `*` match any key starting
+/// with the rest of the pattern.
+fn matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => pattern == key,
+    }
+}
+
+/// Parse a Spark size string like `512m` or `4g` into MiB. Plain numbers are MiB, like
+/// Spark's memory settings. `None` if it doesn't parse or is too large.
+pub fn parse_memory_mib(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let kib = match unit {
+        "b" => return Some(number / (1024 * 1024)),
+        "k" | "kb" => number,
+        "" | "m" | "mb" => number.checked_mul(1024)?,
+        "g" | "gb" => number.checked_mul(1024 * 1024)?,
+        "t" | "tb" => number.checked_mul(1024 * 1024 * 1024)?,
+        "p" | "pb" => number.checked_mul(1024 * 1024 * 1024 * 1024)?,
+        _ => return None,
+    };
+    Some(kib / 1024)
+}
+
+fn bounds_message(min: Option<String>, max: Option<String>) -> String {
+    match (min, max) {
+        (Some(min), Some(max)) => format!("Value must be between {} and {}", min, max),
+        (Some(min), None) => format!("Value must be at least {}", min),
+        (None, Some(max)) => format!("Value must be at most {}", max),
+        (None, None) => "Value is out of range".to_string(),
+    }
+}
+
+impl ValueRule {
+    /// Check the rule's bounds parse. Panics if they don't. Regexes are already compiled
+    /// when the config is loaded.
+    pub fn validate(&self, key: &str) {
+        match self {
+            ValueRule::Regex { .. } => (),
+            ValueRule::Range { min, max } => {
+                for bound in [min, max].into_iter().flatten() {
+                    assert!(
+                        bound.is_finite(),
+                        "Invalid range bound for config {}: {}",
+                        key,
+                        bound
+                    );
+                }
+            }
+            ValueRule::Memory { min, max } => {
+                for bound in [min, max].into_iter().flatten() {
+                    assert!(
+                        parse_memory_mib(bound).is_some(),
+                        "Invalid memory bound for config {}: {}",
+                        key,
+                        bound
+                    );
+                }
+            }
+        }
+    }
+
+    /// Why a value breaks the rule, if it does
+    fn check(&self, value: &str) -> Option<String> {
+        match self {
+            ValueRule::Regex { pattern } => (!pattern.regex.is_match(value))
+                .then(|| format!("Value must match {}", pattern.pattern)),
+            ValueRule::Range { min, max } => {
+                // NaN would pass any bounds, and Spark doesn't take infinite values
+                let Some(number) = value
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|number| number.is_finite())
+                else {
+                    return Some("Value must be a number".to_string());
+                };
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    return Some(bounds_message(
+                        min.map(|min| min.to_string()),
+                        max.map(|max| max.to_string()),
+                    ));
+                }
+                None
+            }
+            ValueRule::Memory { min, max } => {
+                let Some(mib) = parse_memory_mib(value) else {
+                    return Some("Value must be a size like 512m or 4g".to_string());
+                };
+                let min_mib = min.as_deref().and_then(parse_memory_mib);
+                let max_mib = max.as_deref().and_then(parse_memory_mib);
+                if min_mib.is_some_and(|min| mib < min) || max_mib.is_some_and(|max| mib > max) {
+                    return Some(bounds_message(min.clone(), max.clone()));
+                }
+                None
+            }
+        }
+    }
+}
+
+impl ConfigPolicy {
+    /// Check every user provided config, returning the ones that were rejected
+    pub fn check(&self, user_config: &HashMap<String, String>) -> Vec<RejectedConfig> {
+        let mut rejected: Vec<RejectedConfig> = user_config
+            .iter()
+            .filter_map(|(key, value)| {
+                let reason = self.check_key(key, value)?;
+                Some(RejectedConfig {
+                    key: key.clone(),
+                    reason,
+                })
+            })
+            .collect();
+        rejected.sort_by(|a, b| a.key.cmp(&b.key));
+        rejected
+    }
+
+    fn check_key(&self, key: &str, value: &str) -> Option<String> {
+        if self.denied.iter().any(|pattern| matches(pattern, key)) {
+            return Some("Config is denied".to_string());
+        }
+        if let Some(allowed) = self.allowed.as_ref() {
+            if !allowed.iter().any(|pattern| matches(pattern, key)) {
+                return Some("Config is not allowed".to_string());
+            }
+        }
+        self.values
+            .iter()
+            .filter(|(pattern, _)| matches(pattern, key))
+            .find_map(|(_, rule)| rule.check(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn policy(values: serde_json::Value) -> ConfigPolicy {
+        serde_json::from_value(json!({ "values": values })).unwrap()
+    }
+
+    fn check(policy: &ConfigPolicy, key: &str, value: &str) -> Option<String> {
+        policy
+            .check(&HashMap::from([(key.to_string(), value.to_string())]))
+            .pop()
+            .map(|rejected| rejected.reason)
+    }
+
+    #[test]
+    fn parses_memory_sizes() {
+        assert_eq!(parse_memory_mib("512"), Some(512));
+        assert_eq!(parse_memory_mib("512m"), Some(512));
+        assert_eq!(parse_memory_mib(" 4G "), Some(4096));
+        assert_eq!(parse_memory_mib("2048k"), Some(2));
+        assert_eq!(parse_memory_mib("1t"), Some(1024 * 1024));
+        assert_eq!(parse_memory_mib("1073741824b"), Some(1024));
+        assert_eq!(parse_memory_mib("4x"), None);
+        assert_eq!(parse_memory_mib("-1g"), None);
+        // Would overflow once converted
+        assert_eq!(parse_memory_mib("18446744073709551615p"), None);
+        assert_eq!(parse_memory_mib("18446744073709551615g"), None);
+    }
+
+    #[test]
+    fn checks_memory_bounds() {
+        let policy = policy(json!({
+            "spark.driver.memory": {"type": "memory", "min": "1g", "max": "8g"},
+        }));
+        assert_eq!(check(&policy, "spark.driver.memory", "4g"), None);
+        assert_eq!(
+            check(&policy, "spark.driver.memory", "16g").as_deref(),
+            Some("Value must be between 1g and 8g")
+        );
+        assert_eq!(
+            check(&policy, "spark.driver.memory", "18446744073709551615p").as_deref(),
+            Some("Value must be a size like 512m or 4g")
+        );
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers() {
+        let policy = policy(json!({
+            "spark.executor.cores": {"type": "range", "min": 1, "max": 8},
+        }));
+        assert_eq!(check(&policy, "spark.executor.cores", "4"), None);
+        assert_eq!(
+            check(&policy, "spark.executor.cores", "9").as_deref(),
+            Some("Value must be between 1 and 8")
+        );
+        for value in ["NaN", "inf", "-infinity", "four"] {
+            assert_eq!(
+                check(&policy, "spark.executor.cores", value).as_deref(),
+                Some("Value must be a number")
+            );
+        }
+    }
+
+    #[test]
+    fn matches_whole_values() {
+        let policy = policy(json!({
+            "spark.app.name": {"type": "regex", "pattern": "[a-z]+|test"},
+        }));
+        assert_eq!(check(&policy, "spark.app.name", "etl"), None);
+        assert_eq!(check(&policy, "spark.app.name", "test"), None);
+        assert_eq!(
+            check(&policy, "spark.app.name", "etl-2").as_deref(),
+            Some("Value must match [a-z]+|test")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regexes_when_loaded() {
+        let policy = serde_json::from_value::<ConfigPolicy>(json!({
+            "values": {"spark.app.name": {"type": "regex", "pattern": "[a-z"}},
+        }));
+        assert!(policy.is_err());
+    }
+
+    #[test]
+    fn denies_before_allowing() {
+        let policy: ConfigPolicy = serde_json::from_value(json!({
+            "allowed": ["spark.sql.*", "spark.executor.memory"],
+            "denied": ["spark.sql.warehouse.dir"],
+        }))
+        .unwrap();
+        assert_eq!(check(&policy, "spark.sql.shuffle.partitions", "10"), None);
+        assert_eq!(check(&policy, "spark.executor.memory", "4g"), None);
+        assert_eq!(
+            check(&policy, "spark.sql.warehouse.dir", "/tmp").as_deref(),
+            Some("Config is denied")
+        );
+        assert_eq!(
+            check(&policy, "spark.driver.memory", "4g").as_deref(),
+            Some("Config is not allowed")
+        );
+    }
+}
@@ -0,0 +1,542 @@
+/// Module for proxying Spark Connect calls to session drivers
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use axum::{body::Body, Router};
+use futures_util::{stream, StreamExt};
+use http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Bytes, Incoming},
+    service::Service,
+    Request, Response,
+};
+use log::warn;
+use tower::Service as TowerService;
+
+use crate::{
+    auth::ClientCertificate,
+    config::{ProxyConfig, RouteBy},
+    grpc::{self, Code, RequestContext, MESSAGE_PREFIX_LEN},
+    store::{wait_until_started, Session, SessionState, SessionStore},
+    upstream::UpstreamPool,
+};
+
+// Most of a request body read while looking for its session ID or user ID
+const MAX_PEEK_BYTES: usize = 64 * 1024;
+
+/// State shared by every client connection
+pub struct ProxyContext {
+    session_store: Arc<dyn SessionStore>,
+    upstreams: Arc<UpstreamPool>,
+    ready_timeout: Duration,
+    route_by: Vec<RouteBy>,
+}
+
+impl ProxyContext {
+    pub fn from_config(
+        config: &ProxyConfig,
+        session_store: Arc<dyn SessionStore>,
+        upstreams: Arc<UpstreamPool>,
+    ) -> Self {
+        Self {
+            session_store,
+            upstreams,
+            ready_timeout: config.get_ready_timeout(),
+            route_by: config.get_route_by(),
+        }
+    }
+}
+
+/// Serves a client connection. Each Spark Connect call is routed to the driver of the
+/// session its token belongs to, and everything else goes to the API router.
+pub struct ProxyService {
+    router: Router,
+    context: Arc<ProxyContext>,
+    client_cert: Option<ClientCertificate>,
+}
+
+impl ProxyService {
+    pub fn new(
+        router: Router,
+        context: Arc<ProxyContext>,
+        client_cert: Option<ClientCertificate>,
+    ) -> Self {
+        Self {
+            router,
+            context,
+            client_cert,
+        }
+    }
+
+    /// Find the possible session tokens for a call in the places configured by
+    /// `route_by`, in order. The request body is only read into if a token might be
+    /// inside it.
+    async fn route(
+        route_by: &[RouteBy],
+        req: Request<Incoming>,
+    ) -> Result<(Vec<String>, Request<Body>), Response<Body>> {
+        let (parts, body) = req.into_parts();
+        let header_token = bearer_token(&parts.headers);
+
+        let needs_context = route_by.iter().any(|route| *route != RouteBy::Token);
+        let (context, body) = if needs_context {
+            peek_context(body).await?
+        } else {
+            (RequestContext::default(), Body::new(body))
+        };
+
+        let mut tokens: Vec<String> = Vec::new();
+        for route in route_by {
+            let token = match route {
+                RouteBy::Token => header_token.as_ref(),
+                RouteBy::SessionId => context.session_id.as_ref(),
+                RouteBy::UserId => context.user_id.as_ref(),
+            };
+            if let Some(token) = token.filter(|token| !tokens.contains(token)) {
+                tokens.push(token.clone());
+            }
+        }
+
+        if tokens.is_empty() {
+            return Err(grpc::error_response(
+                Code::Unauthenticated,
+                "A session token must be provided with the request",
+            ));
+        }
+        Ok((tokens, Request::from_parts(parts, body)))
+    }
+
+    /// Pick the token a call is for. A token with an open upstream connection wins, then
+    /// the first one that belongs to a session.
+    async fn choose_token(
+        context: &ProxyContext,
+        mut tokens: Vec<String>,
+    ) -> Result<String, Response<Body>> {
+        if tokens.len() == 1 {
+            return Ok(tokens.remove(0));
+        }
+
+        if let Some(token) = tokens
+            .iter()
+            .find(|token| context.upstreams.find(token).is_some())
+        {
+            return Ok(token.clone());
+        }
+
+        for token in tokens.iter() {
+            match context.session_store.get_session_by_token(token).await {
+                Ok(Some(session)) if !session.is_token_expired() => return Ok(token.clone()),
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("Failed to look up session: {}", e);
+                    return Err(grpc::error_response(
+                        Code::Internal,
+                        "Failed to look up session",
+                    ));
+                }
+            }
+        }
+        Err(grpc::error_response(
+            Code::NotFound,
+            "No session found for this token",
+        ))
+    }
+
+    /// Look up the ready session for a token along with its driver's address and the
+    /// authorization the driver expects, or the gRPC error to reject the call with
+    async fn find_session(
+        context: &ProxyContext,
+        token: &str,
+    ) -> Result<(Session, String, HeaderValue), Response<Body>> {
+        // Calls made while the session is starting up are held until it's ready
+        let session = wait_until_started(
+            context.session_store.as_ref(),
+            context.ready_timeout,
+            || context.session_store.get_session_by_token(token),
+        )
+        .await
+        .map_err(|e| {
+            warn!("Failed to look up session: {}", e);
+            grpc::error_response(Code::Internal, "Failed to look up session")
+        })?
+        .ok_or_else(|| grpc::error_response(Code::NotFound, "No session found for this token"))?;
+
+        if session.is_token_expired() {
+            return Err(grpc::error_response(
+                Code::Unauthenticated,
+                "Session token has expired",
+            ));
+        }
+
+        // The driver checks the token it was launched with itself
+        let driver_token = session.driver_token.as_deref().unwrap_or(token);
+        let Ok(authorization) = HeaderValue::from_str(&format!("Bearer {}", driver_token)) else {
+            return Err(grpc::error_response(
+                Code::Unauthenticated,
+                "Invalid session token",
+            ));
+        };
+
+        match (&session.state, &session.addr) {
+            (SessionState::Ready | SessionState::Idle, Some(addr)) => {
+                let addr = addr.clone();
+                Ok((session, addr, authorization))
+            }
+            (SessionState::Failed { reason }, _) => Err(grpc::error_response(
+                Code::Unavailable,
+                &format!("Session {} failed: {}", session.id, reason),
+            )),
+            (state, _) => Err(grpc::error_response(
+                Code::Unavailable,
+                &format!(
+                    "Session {} is not ready, it is {}",
+                    session.id,
+                    state.name()
+                ),
+            )),
+        }
+    }
+
+    async fn dispatch(context: Arc<ProxyContext>, req: Request<Incoming>) -> Response<Body> {
+        let (tokens, req) = match Self::route(&context.route_by, req).await {
+            Ok(routed) => routed,
+            Err(response) => return response,
+        };
+        let token = match Self::choose_token(&context, tokens).await {
+            Ok(token) => token,
+            Err(response) => return response,
+        };
+
+        let upstream = match context.upstreams.find(&token) {
+            Some(upstream) => upstream,
+            None => match Self::find_session(&context, &token).await {
+                Ok((session, addr, authorization)) => context.upstreams.connect(
+                    &token,
+                    session.id,
+                    addr,
+                    authorization,
+                    session.token_expires_at,
+                ),
+                Err(response) => return response,
+            },
+        };
+
+        upstream.send(req).await
+    }
+}
+
+impl Service<Request<Incoming>> for ProxyService {
+    type Response = Response<Body>;
+
+    type Error = hyper::Error;
+
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        if req
+            .uri()
+            .path()
+            .starts_with("/spark.connect.SparkConnectService")
+        {
+            let context = self.context.clone();
+            Box::pin(async move { Ok(Self::dispatch(context, req).await) })
+        } else {
+            if let Some(client_cert) = self.client_cert.clone() {
+                req.extensions_mut().insert(client_cert);
+            }
+            let mut router = self.router.clone();
+            Box::pin(async move {
+                let Ok(response) = router.call(req).await;
+                Ok(response)
+            })
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+}
+
+/// Read enough of a request body to parse the context of its first message, and return
+/// a body that replays what was read
+async fn peek_context(mut body: Incoming) -> Result<(RequestContext, Body), Response<Body>> {
+    let mut buf = Vec::new();
+    while buf.len() < MAX_PEEK_BYTES {
+        match grpc::message_len(&buf) {
+            Some(len) if buf.len() >= MESSAGE_PREFIX_LEN + len => break,
+            // A compressed message
+            None if buf.len() >= MESSAGE_PREFIX_LEN => break,
+            _ => (),
+        }
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    buf.extend_from_slice(data);
+                }
+            }
+            Some(Err(err)) => {
+                warn!("Failed to read request body: {}", err);
+                return Err(grpc::error_response(
+                    Code::Internal,
+                    "Failed to read the request",
+                ));
+            }
+            None => break,
+        }
+    }
+
+    let context = match grpc::message_len(&buf) {
+        Some(len) => {
+            let end = buf.len().min(MESSAGE_PREFIX_LEN + len);
+            RequestContext::parse(&buf[MESSAGE_PREFIX_LEN..end])
+        }
+        None => RequestContext::default(),
+    };
+
+    let read = Bytes::from(buf);
+    let body = Body::from_stream(
+        stream::once(async move { Ok::<_, hyper::Error>(read) }).chain(body.into_data_stream()),
+    );
+    Ok((context, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use hyper::client::conn::http2::SendRequest;
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto::Builder,
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{
+        fake_driver::{Behavior, FakeDriver},
+        idle::IdleSessions,
+        store::{now, InMemorySessionStore},
+    };
+
+    /// A proxy serving on a local port, and a client connected to it
+    struct Harness {
+        session_store: Arc<dyn SessionStore>,
+        client: SendRequest<Body>,
+    }
+
+    impl Harness {
+        async fn start(ready_timeout: u64) -> Self {
+            let config = ProxyConfig {
+                ready_timeout: Some(ready_timeout),
+                ..Default::default()
+            };
+            let session_store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::default());
+            let upstreams = Arc::new(UpstreamPool::new(Arc::new(IdleSessions::from_config(
+                &config,
+            ))));
+            let context = Arc::new(ProxyContext::from_config(
+                &config,
+                session_store.clone(),
+                upstreams,
+            ));
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::task::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let service = ProxyService::new(Router::new(), context.clone(), None);
+                    tokio::task::spawn(async move {
+                        let _ = Builder::new(TokioExecutor::new())
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (client, conn) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::task::spawn(conn);
+            Self {
+                session_store,
+                client,
+            }
+        }
+
+        async fn session(&self, token: &str, expires_at: Option<u64>, states: &[SessionState]) {
+            let session = self
+                .session_store
+                .create_session("alice", token.to_string(), expires_at, None, HashMap::new())
+                .await
+                .unwrap();
+            for state in states {
+                self.session_store
+                    .set_session_state(session.id, state.clone())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        async fn ready(&self, token: &str, addr: &str) {
+            self.session(token, None, &[SessionState::Launching]).await;
+            let session = self
+                .session_store
+                .get_session_by_token(token)
+                .await
+                .unwrap()
+                .unwrap();
+            self.session_store
+                .set_session_driver_token(session.id, "driver-token".to_string())
+                .await
+                .unwrap();
+            self.session_store
+                .set_session_ready(session.id, addr.to_string())
+                .await
+                .unwrap();
+        }
+
+        async fn call(&mut self, token: Option<&str>) -> (String, String, HeaderMap) {
+            let mut request = Request::builder()
+                .uri("http://proxy/spark.connect.SparkConnectService/ExecutePlan")
+                .header("content-type", "application/grpc");
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let response = self
+                .client
+                .send_request(request.body(Body::from("plan")).unwrap())
+                .await
+                .unwrap();
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .map(|value: &HeaderValue| value.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            };
+            (
+                header("grpc-status"),
+                header("grpc-message"),
+                response.headers().clone(),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_calls_without_a_token() {
+        let mut harness = Harness::start(0).await;
+        let (status, message, _) = harness.call(None).await;
+        assert_eq!(status, "16");
+        assert_eq!(message, "A session token must be provided with the request");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_tokens() {
+        let mut harness = Harness::start(0).await;
+        let (status, message, _) = harness.call(Some("unknown")).await;
+        assert_eq!(status, "5");
+        assert_eq!(message, "No session found for this token");
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_tokens() {
+        let mut harness = Harness::start(0).await;
+        harness.session("expired", Some(now() - 1), &[]).await;
+        let (status, message, _) = harness.call(Some("expired")).await;
+        assert_eq!(status, "16");
+        assert_eq!(message, "Session token has expired");
+    }
+
+    #[tokio::test]
+    async fn rejects_sessions_that_never_become_ready() {
+        let mut harness = Harness::start(0).await;
+        harness.session("pending", None, &[]).await;
+        harness
+            .session("launching", None, &[SessionState::Launching])
+            .await;
+
+        let (status, message, _) = harness.call(Some("pending")).await;
+        assert_eq!(status, "14");
+        assert_eq!(message, "Session 0 is not ready, it is pending");
+        let (status, message, _) = harness.call(Some("launching")).await;
+        assert_eq!(status, "14");
+        assert_eq!(message, "Session 1 is not ready, it is launching");
+    }
+
+    #[tokio::test]
+    async fn rejects_failed_sessions() {
+        let mut harness = Harness::start(0).await;
+        harness
+            .session(
+                "failed",
+                None,
+                &[
+                    SessionState::Launching,
+                    SessionState::Failed {
+                        reason: "Driver exited with exit status: 1".to_string(),
+                    },
+                ],
+            )
+            .await;
+
+        let (status, message, _) = harness.call(Some("failed")).await;
+        assert_eq!(status, "14");
+        assert_eq!(
+            message,
+            "Session 0 failed: Driver exited with exit status: 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_calls_to_unreachable_drivers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let mut harness = Harness::start(0).await;
+        harness.ready("token", &addr).await;
+        let (status, message, _) = harness.call(Some("token")).await;
+        assert_eq!(status, "14");
+        assert_eq!(message, "Unable to connect to the Spark driver");
+    }
+
+    #[tokio::test]
+    async fn holds_calls_until_the_session_is_ready() {
+        let driver = FakeDriver::start(Behavior::Respond(Duration::ZERO)).await;
+        let mut harness = Harness::start(5).await;
+        harness
+            .session("token", None, &[SessionState::Launching])
+            .await;
+
+        let session_store = harness.session_store.clone();
+        let addr = driver.addr.clone();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            session_store.set_session_ready(0, addr).await.unwrap();
+        });
+
+        let (status, _, headers) = harness.call(Some("token")).await;
+        assert_eq!(status, "0");
+        // Sessions without a driver token of their own present the session's token
+        assert_eq!(headers["x-authorization"], "Bearer token");
+    }
+
+    #[tokio::test]
+    async fn routes_calls_to_the_driver() {
+        let driver = FakeDriver::start(Behavior::Respond(Duration::ZERO)).await;
+        let mut harness = Harness::start(0).await;
+        harness.ready("token", &driver.addr).await;
+
+        let (status, _, headers) = harness.call(Some("token")).await;
+        assert_eq!(status, "0");
+        assert_eq!(headers["x-authorization"], "Bearer driver-token");
+    }
+}
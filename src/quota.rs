@@ -0,0 +1,513 @@
+/// Module for limiting the sessions that can run at once
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::ProxyConfig,
+    launcher::Launcher,
+    policy::parse_memory_mib,
+    store::{SessionState, SessionStore, StoreResult},
+    warm::WARM_USERNAME,
+};
+
+// Spark's defaults for drivers that don't set their resources
+const DEFAULT_DRIVER_MEMORY_MIB: u64 = 1024;
+const DEFAULT_DRIVER_CORES: u64 = 1;
+
+/// What a session counts for against the limits while it runs
+#[derive(Clone, Debug)]
+pub struct Demand {
+    pub username: String,
+    pub version: String,
+    pub memory_mib: u64,
+    pub cores: u64,
+}
+
+impl Demand {
+    /// Work out a session's demand from the configs its driver runs with
+    pub fn new(username: &str, version: String, configs: &HashMap<String, String>) -> Self {
+        Self {
+            username: username.to_string(),
+            version,
+            memory_mib: configs
+                .get("spark.driver.memory")
+                .and_then(|memory| parse_memory_mib(memory))
+                .unwrap_or(DEFAULT_DRIVER_MEMORY_MIB),
+            cores: configs
+                .get("spark.driver.cores")
+                .and_then(|cores| cores.trim().parse().ok())
+                .unwrap_or(DEFAULT_DRIVER_CORES),
+        }
+    }
+}
+
+/// A session waiting for capacity, with what's needed to launch it
+pub struct QueuedLaunch {
+    pub session_id: u64,
+    pub demand: Demand,
+    // The configs the user asked for
+    pub config: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct Usage {
+    // Sessions counted against the limits by session ID
+    admitted: HashMap<u64, Demand>,
+    // Admitted sessions kept counted while their driver is restarted
+    held: HashSet<u64>,
+    // Bumped whenever a session is held or let go, so a session isn't released based on
+    // a state read from before its driver was restarted
+    hold_generations: HashMap<u64, u64>,
+    queue: VecDeque<QueuedLaunch>,
+}
+
+/// Admits sessions while they fit within the configured limits. Sessions over the limits
+/// are either rejected or queued, and queued sessions are launched as others finish.
+pub struct Quotas {
+    max_sessions: Option<usize>,
+    max_sessions_per_user: Option<usize>,
+    max_driver_memory_mib: Option<u64>,
+    max_driver_cores: Option<u64>,
+    version_max_sessions: HashMap<String, usize>,
+    queue: bool,
+    max_queued: Option<usize>,
+    usage: Mutex<Usage>,
+}
+
+impl Quotas {
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        let quotas = &config.quotas;
+        let max_driver_memory_mib = quotas.max_driver_memory.as_ref().map(|memory| {
+            parse_memory_mib(memory)
+                .unwrap_or_else(|| panic!("Invalid max_driver_memory: {}", memory))
+        });
+
+        Self {
+            max_sessions: quotas.max_sessions,
+            max_sessions_per_user: quotas.max_sessions_per_user,
+            max_driver_memory_mib,
+            max_driver_cores: quotas.max_driver_cores,
+            version_max_sessions: config
+                .spark_versions
+                .iter()
+                .filter_map(|version| Some((version.name.clone(), version.max_sessions?)))
+                .collect(),
+            queue: quotas.queue,
+            max_queued: quotas.max_queued,
+            usage: Mutex::new(Usage::default()),
+        }
+    }
+
+    pub fn is_queueing(&self) -> bool {
+        self.queue
+    }
+
+    /// Why a session could never fit, even once every other session has stopped
+    fn never_fits(&self, demand: &Demand) -> Option<String> {
+        if self
+            .max_driver_memory_mib
+            .is_some_and(|max| demand.memory_mib > max)
+        {
+            return Some(format!(
+                "Session needs {}m of driver memory, more than the limit of {}m",
+                demand.memory_mib,
+                self.max_driver_memory_mib.unwrap_or_default()
+            ));
+        }
+        if self.max_driver_cores.is_some_and(|max| demand.cores > max) {
+            return Some(format!(
+                "Session needs {} driver cores, more than the limit of {}",
+                demand.cores,
+                self.max_driver_cores.unwrap_or_default()
+            ));
+        }
+        None
+    }
+
+    /// Why a session doesn't fit alongside the ones already admitted, if it doesn't
+    fn exceeded(&self, admitted: &HashMap<u64, Demand>, demand: &Demand) -> Option<String> {
+        if let Some(reason) = self.never_fits(demand) {
+            return Some(reason);
+        }
+        if self.max_sessions.is_some_and(|max| admitted.len() >= max) {
+            return Some("Too many sessions are running".to_string());
+        }
+        if let Some(max) = self.max_sessions_per_user {
+            let count = admitted
+                .values()
+                .filter(|other| other.username == demand.username)
+                .count();
+            if count >= max {
+                return Some(format!(
+                    "User {} already has {} sessions running",
+                    demand.username, count
+                ));
+            }
+        }
+        if let Some(max) = self.version_max_sessions.get(&demand.version) {
+            let count = admitted
+                .values()
+                .filter(|other| other.version == demand.version)
+                .count();
+            if count >= *max {
+                return Some(format!(
+                    "Version {} already has {} sessions running",
+                    demand.version, count
+                ));
+            }
+        }
+        if let Some(max) = self.max_driver_memory_mib {
+            let used: u64 = admitted.values().map(|other| other.memory_mib).sum();
+            if used + demand.memory_mib > max {
+                return Some(format!(
+                    "Not enough driver memory left, {}m of {}m is in use",
+                    used, max
+                ));
+            }
+        }
+        if let Some(max) = self.max_driver_cores {
+            let used: u64 = admitted.values().map(|other| other.cores).sum();
+            if used + demand.cores > max {
+                return Some(format!(
+                    "Not enough driver cores left, {} of {} are in use",
+                    used, max
+                ));
+            }
+        }
+        None
+    }
+
+    /// Whether a session would fit alongside the ones already admitted
+    pub fn fits(&self, demand: &Demand) -> bool {
+        let usage = self.usage.lock().unwrap();
+        self.exceeded(&usage.admitted, demand).is_none()
+    }
+
+    /// Count a session against the limits, or return why it doesn't fit
+    pub fn admit(&self, session_id: u64, demand: Demand) -> Result<(), String> {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(reason) = self.exceeded(&usage.admitted, &demand) {
+            return Err(reason);
+        }
+        usage.admitted.insert(session_id, demand);
+        Ok(())
+    }
+
+    /// Keep counting a session while its driver is stopped to be started again. Returns
+    /// `false` if the session isn't counted, in which case it has to be admitted again.
+    pub fn hold(&self, session_id: u64) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        if !usage.admitted.contains_key(&session_id) {
+            return false;
+        }
+        usage.held.insert(session_id);
+        *usage.hold_generations.entry(session_id).or_default() += 1;
+        true
+    }
+
+    /// Let a session be released again once it finishes. Only call once the restarted
+    /// driver's session is launching, or has failed to.
+    pub fn unhold(&self, session_id: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.held.remove(&session_id) {
+            *usage.hold_generations.entry(session_id).or_default() += 1;
+        }
+    }
+
+    /// Queue a session to be launched once it fits, returning its position in the queue
+    pub fn enqueue(&self, launch: QueuedLaunch) -> Result<usize, String> {
+        if let Some(reason) = self.never_fits(&launch.demand) {
+            return Err(reason);
+        }
+        let mut usage = self.usage.lock().unwrap();
+        if self.max_queued.is_some_and(|max| usage.queue.len() >= max) {
+            return Err("Too many sessions are queued".to_string());
+        }
+        usage.queue.push_back(launch);
+        Ok(usage.queue.len())
+    }
+
+    /// Count the sessions persisted before a restart of the proxy. Running ones are
+    /// counted even if they no longer fit, and ones that were waiting for capacity are
+    /// queued again. Warm sessions aren't counted, like before they're handed out.
+    pub async fn recover(
+        &self,
+        session_store: &dyn SessionStore,
+        launcher: &dyn Launcher,
+    ) -> StoreResult<()> {
+        for (username, session) in session_store.list_unfinished_sessions().await? {
+            if username == WARM_USERNAME {
+                continue;
+            }
+            let demand = match launcher
+                .effective_config(session.version.as_deref(), session.config.clone())
+            {
+                Ok((version_name, effective_config)) => {
+                    Demand::new(&username, version_name, &effective_config)
+                }
+                Err(e) => {
+                    // Still counted as best it can be, since its driver may be running
+                    warn!(
+                        "Failed to work out the demand of session {}: {}",
+                        session.id, e
+                    );
+                    Demand::new(
+                        &username,
+                        session.version.clone().unwrap_or_default(),
+                        &session.config,
+                    )
+                }
+            };
+
+            let mut usage = self.usage.lock().unwrap();
+            if session.state == SessionState::Pending {
+                info!("Queueing session {} again", session.id);
+                usage.queue.push_back(QueuedLaunch {
+                    session_id: session.id,
+                    demand,
+                    config: session.config,
+                });
+            } else {
+                usage.admitted.insert(session.id, demand);
+            }
+        }
+        Ok(())
+    }
+
+    /// Watch for sessions finishing, and launch queued sessions as they fit
+    pub fn spawn_scheduler(
+        self: &Arc<Self>,
+        session_store: Arc<dyn SessionStore>,
+        launcher: Arc<dyn Launcher>,
+    ) {
+        let quotas = self.clone();
+        let mut changes = session_store.state_changes();
+        tokio::task::spawn(async move {
+            // Sessions may have been queued before the scheduler started
+            loop {
+                quotas.release_finished(session_store.as_ref()).await;
+
+                for launch in quotas.admit_queued() {
+                    info!("Launching queued session {}", launch.session_id);
+                    let launcher = launcher.clone();
+                    tokio::task::spawn(async move {
+                        if let Err(e) = launcher
+                            .launch(
+                                launch.session_id,
+                                Some(&launch.demand.version),
+                                launch.demand.username,
+                                launch.config,
+                            )
+                            .await
+                        {
+                            warn!(
+                                "Failed to launch queued session {}: {}",
+                                launch.session_id, e
+                            );
+                        }
+                    });
+                }
+
+                if changes.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Stop counting sessions that stopped, failed or were deleted, and drop deleted
+    /// sessions from the queue
+    async fn release_finished(&self, session_store: &dyn SessionStore) {
+        for (session_id, username, generation) in self.counted() {
+            let finished = match session_store.get_session(&username, session_id).await {
+                Ok(Some(session)) => session.state.is_finished(),
+                Ok(None) => true,
+                Err(e) => {
+                    warn!("Failed to look up session {}: {}", session_id, e);
+                    false
+                }
+            };
+            if finished {
+                self.release(session_id, generation);
+            }
+        }
+    }
+
+    /// Every admitted or queued session, with its user and hold generation
+    fn counted(&self) -> Vec<(u64, String, u64)> {
+        let usage = self.usage.lock().unwrap();
+        let generation = |session_id: &u64| {
+            usage
+                .hold_generations
+                .get(session_id)
+                .copied()
+                .unwrap_or_default()
+        };
+        usage
+            .admitted
+            .iter()
+            .map(|(id, demand)| (*id, demand.username.clone(), generation(id)))
+            .chain(usage.queue.iter().map(|launch| {
+                (
+                    launch.session_id,
+                    launch.demand.username.clone(),
+                    generation(&launch.session_id),
+                )
+            }))
+            .collect()
+    }
+
+    /// Stop counting a session found finished, unless it's held or was held or let go
+    /// since `generation` was read, when its state may already be out of date
+    fn release(&self, session_id: u64, generation: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        let current = usage
+            .hold_generations
+            .get(&session_id)
+            .copied()
+            .unwrap_or_default();
+        if usage.held.contains(&session_id) || current != generation {
+            return;
+        }
+        usage.admitted.remove(&session_id);
+        usage.hold_generations.remove(&session_id);
+        usage.queue.retain(|launch| launch.session_id != session_id);
+    }
+
+    /// Admit the queued sessions that now fit, in the order they were queued
+    fn admit_queued(&self) -> Vec<QueuedLaunch> {
+        let mut usage = self.usage.lock().unwrap();
+        let mut admitted = Vec::new();
+        let mut waiting = VecDeque::new();
+        while let Some(launch) = usage.queue.pop_front() {
+            if self.exceeded(&usage.admitted, &launch.demand).is_none() {
+                usage
+                    .admitted
+                    .insert(launch.session_id, launch.demand.clone());
+                admitted.push(launch);
+            } else {
+                waiting.push_back(launch);
+            }
+        }
+        usage.queue = waiting;
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{fake_launcher::FakeLauncher, store::InMemorySessionStore};
+
+    fn quotas() -> Quotas {
+        let mut config = ProxyConfig::default();
+        config.quotas.max_sessions = Some(1);
+        Quotas::from_config(&config)
+    }
+
+    fn demand() -> Demand {
+        Demand::new("alice", "3.5".to_string(), &HashMap::new())
+    }
+
+    #[test]
+    fn releases_finished_sessions() {
+        let quotas = quotas();
+        quotas.admit(1, demand()).unwrap();
+        assert!(!quotas.fits(&demand()));
+
+        for (session_id, _, generation) in quotas.counted() {
+            quotas.release(session_id, generation);
+        }
+        assert!(quotas.fits(&demand()));
+    }
+
+    #[test]
+    fn keeps_held_sessions() {
+        let quotas = quotas();
+        quotas.admit(1, demand()).unwrap();
+        assert!(quotas.hold(1));
+
+        let (_, _, generation) = quotas.counted()[0];
+        quotas.release(1, generation);
+        assert!(!quotas.fits(&demand()));
+    }
+
+    #[test]
+    fn keeps_sessions_restarted_since_their_state_was_read() {
+        let quotas = quotas();
+        quotas.admit(1, demand()).unwrap();
+
+        // The state is read as stopped while the driver restarts, and only acted on once
+        // the new driver is launching
+        let (_, _, generation) = quotas.counted()[0];
+        assert!(quotas.hold(1));
+        quotas.unhold(1);
+        quotas.release(1, generation);
+        assert!(!quotas.fits(&demand()));
+
+        // Found finished again later on
+        let (_, _, generation) = quotas.counted()[0];
+        quotas.release(1, generation);
+        assert!(quotas.fits(&demand()));
+    }
+
+    #[test]
+    fn sessions_that_were_released_have_to_be_admitted_again() {
+        let quotas = quotas();
+        assert!(!quotas.hold(1));
+        quotas.admit(1, demand()).unwrap();
+        assert!(quotas.admit(2, demand()).is_err());
+    }
+
+    #[tokio::test]
+    async fn counts_sessions_persisted_before_a_restart() {
+        let session_store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::default());
+        let launcher = FakeLauncher::new(session_store.clone(), Duration::ZERO);
+        for (token, (username, states)) in [
+            ("alice", vec![SessionState::Launching, SessionState::Ready]),
+            (
+                "alice",
+                vec![SessionState::Launching, SessionState::Stopped],
+            ),
+            ("bob", vec![]),
+            (WARM_USERNAME, vec![SessionState::Launching]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let session = session_store
+                .create_session(username, token.to_string(), None, None, HashMap::new())
+                .await
+                .unwrap();
+            for state in states {
+                session_store
+                    .set_session_state(session.id, state)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let quotas = quotas();
+        quotas
+            .recover(session_store.as_ref(), &launcher)
+            .await
+            .unwrap();
+        assert!(!quotas.fits(&demand()));
+        let counted: Vec<(u64, String)> = quotas
+            .counted()
+            .into_iter()
+            .map(|(session_id, username, _)| (session_id, username))
+            .collect();
+        assert_eq!(
+            counted,
+            vec![(0, "alice".to_string()), (2, "bob".to_string())]
+        );
+        assert_eq!(quotas.usage.lock().unwrap().queue[0].session_id, 2);
+    }
+}
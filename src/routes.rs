@@ -1,11 +1,20 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
-    extract::{Path, State},
+    body::Body,
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Extension, Json, Router,
 };
-use http::StatusCode;
+use futures_util::{stream, StreamExt};
+use http::{header::CONTENT_TYPE, StatusCode};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use tower::ServiceBuilder;
@@ -14,15 +23,62 @@ use uuid::Uuid;
 
 use crate::{
     auth::{BearerToken, TokenAuth, UserAuth, UserId},
-    config::ProxyConfig,
-    launcher::Launcher,
-    store::{Session, SessionStore},
+    config::{LauncherConfig, ProxyConfig},
+    idle::IdleSessions,
+    launcher::{CommandTemplateLauncher, Launcher, SparkSubmitLauncher},
+    logs::DriverLogs,
+    policy::RejectedConfig,
+    quota::{Demand, QueuedLaunch, Quotas},
+    store::{self, wait_until_started, Session, SessionState, SessionStore},
+    upstream::UpstreamPool,
+    warm::WarmPool,
 };
 
-pub fn get_router(config: &ProxyConfig, session_store: Arc<dyn SessionStore>) -> Router {
+pub async fn get_router(
+    config: &ProxyConfig,
+    session_store: Arc<dyn SessionStore>,
+    idle_sessions: Arc<IdleSessions>,
+    upstreams: Arc<UpstreamPool>,
+) -> Router {
+    let spark_submit = SparkSubmitLauncher::from_config(
+        config,
+        session_store.clone(),
+        Arc::new(DriverLogs::from_config(config)),
+        idle_sessions.clone(),
+    );
+    spark_submit
+        .recover()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to recover sessions: {}", e));
+    let launcher: Arc<dyn Launcher> = match &config.launcher {
+        LauncherConfig::SparkSubmit => Arc::new(spark_submit),
+        LauncherConfig::CommandTemplate { command } => {
+            Arc::new(CommandTemplateLauncher::new(spark_submit, command.clone()))
+        }
+    };
+    let quotas = Arc::new(Quotas::from_config(config));
+    quotas
+        .recover(session_store.as_ref(), launcher.as_ref())
+        .await
+        .unwrap_or_else(|e| panic!("Failed to recover quota usage: {}", e));
+    quotas.spawn_scheduler(session_store.clone(), launcher.clone());
+    let warm_pool = Arc::new(WarmPool::from_config(
+        config,
+        session_store.clone(),
+        launcher.clone(),
+        idle_sessions.clone(),
+    ));
+    warm_pool.spawn_refill();
     let app_state = AppStateDyn {
-        session_store,
-        launcher: Arc::new(Launcher::from_config(config)),
+        session_store: session_store.clone(),
+        launcher,
+        quotas,
+        warm_pool,
+        idle_sessions,
+        upstreams,
+        busy_sessions: Arc::new(BusySessions::default()),
+        ready_timeout: config.get_ready_timeout(),
+        token_ttl: config.token_ttl,
     };
 
     let user_api = Router::new()
@@ -31,8 +87,17 @@ pub fn get_router(config: &ProxyConfig, session_store: Arc<dyn SessionStore>) ->
             "/sessions/:session_id",
             get(get_session).delete(delete_session),
         )
+        .route("/sessions/:session_id/logs", get(get_session_logs))
+        .route("/sessions/:session_id/ready", get(wait_for_session))
+        .route("/sessions/:session_id/app", get(get_session_app))
+        .route("/sessions/:session_id/restart", post(restart_session))
+        .route("/sessions/:session_id/token", post(rotate_session_token))
         .route("/versions", get(list_versions))
-        .route_layer(ServiceBuilder::new().layer(AsyncRequireAuthorizationLayer::new(UserAuth {})))
+        .route_layer(
+            ServiceBuilder::new().layer(AsyncRequireAuthorizationLayer::new(
+                UserAuth::from_config(config),
+            )),
+        )
         .with_state(app_state.clone());
 
     let callback_api = Router::new()
@@ -43,10 +108,110 @@ pub fn get_router(config: &ProxyConfig, session_store: Arc<dyn SessionStore>) ->
     Router::new().merge(user_api).merge(callback_api)
 }
 
+fn internal_error(e: impl Display) -> StatusCode {
+    warn!("{}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
 #[derive(Clone)]
 struct AppStateDyn {
     session_store: Arc<dyn SessionStore>,
-    launcher: Arc<Launcher>,
+    launcher: Arc<dyn Launcher>,
+    quotas: Arc<Quotas>,
+    warm_pool: Arc<WarmPool>,
+    idle_sessions: Arc<IdleSessions>,
+    upstreams: Arc<UpstreamPool>,
+    busy_sessions: Arc<BusySessions>,
+    ready_timeout: Duration,
+    // Seconds session tokens are accepted for
+    token_ttl: Option<u64>,
+}
+
+impl AppStateDyn {
+    /// When a token handed out now expires, if tokens expire
+    fn token_expires_at(&self) -> Option<u64> {
+        self.token_ttl.map(|ttl| store::now() + ttl)
+    }
+}
+
+/// Sessions being restarted or deleted, so only one of those runs for a session at a time
+/// and each driver launched is the only one for its session
+#[derive(Default)]
+struct BusySessions(Mutex<HashSet<u64>>);
+
+/// Marks a session busy for as long as it's held
+struct BusyGuard {
+    busy_sessions: Arc<BusySessions>,
+    session_id: u64,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.busy_sessions
+            .0
+            .lock()
+            .unwrap()
+            .remove(&self.session_id);
+    }
+}
+
+impl BusySessions {
+    /// Mark a session busy, or `None` if it already is
+    fn start(self: &Arc<Self>, session_id: u64) -> Option<BusyGuard> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(session_id)
+            .then(|| BusyGuard {
+                busy_sessions: self.clone(),
+                session_id,
+            })
+    }
+}
+
+fn busy_response(session_id: u64) -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(ErrorResponse {
+            error: format!(
+                "Session {} is already being restarted or deleted",
+                session_id
+            ),
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct WaitParams {
+    // Wait for the session to finish starting up before responding
+    #[serde(default)]
+    wait: bool,
+    // Seconds to wait, defaults to `ready_timeout`
+    timeout: Option<u64>,
+}
+
+impl WaitParams {
+    fn timeout(&self, state: &AppStateDyn) -> Duration {
+        self.timeout
+            .map(Duration::from_secs)
+            .unwrap_or(state.ready_timeout)
+    }
+}
+
+/// Wait for a session to finish starting up, returning its state at that point
+async fn wait_for_state(
+    state: &AppStateDyn,
+    username: &str,
+    session_id: u64,
+    timeout: Duration,
+) -> Result<Session, StatusCode> {
+    wait_until_started(state.session_store.as_ref(), timeout, || {
+        state.session_store.get_session(username, session_id)
+    })
+    .await
+    .map_err(internal_error)?
+    .ok_or(StatusCode::NOT_FOUND)
 }
 
 #[allow(dead_code)]
@@ -58,32 +223,189 @@ struct CreateSessionRequest {
 
 #[derive(Serialize)]
 struct CreateSessionResponse {
+    id: u64,
     token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_expires_at: Option<u64>,
+    #[serde(flatten)]
+    state: SessionState,
+    // Position in the queue if the session is waiting for capacity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_position: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct ConfigRejectedResponse {
+    error: String,
+    rejected: Vec<RejectedConfig>,
 }
 
+/// Create and launch a session. With `wait`, the response is held until the session is
+/// ready, fails, or the timeout passes. Sessions that can use a warm driver get it and
+/// are ready right away. Responds with 400 listing the rejected configs if any break the
+/// version's policy, and 429 if the session is over a quota. With queueing enabled,
+/// sessions over a quota respond with 202 instead and are launched later.
 async fn create_session(
     State(state): State<AppStateDyn>,
     Extension(user): Extension<UserId>,
+    Query(wait): Query<WaitParams>,
     Json(params): Json<CreateSessionRequest>,
-) -> Result<Json<CreateSessionResponse>, StatusCode> {
-    let token = Uuid::new_v4().to_string();
-    state.session_store.create_session(&user.0, token.clone());
+) -> Result<Response, StatusCode> {
+    let config = params.config.unwrap_or_default();
+    let rejected = state
+        .launcher
+        .check_config(params.version.as_deref(), &config);
+    if !rejected.is_empty() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(ConfigRejectedResponse {
+                error: "Configs rejected by policy".to_string(),
+                rejected,
+            }),
+        )
+            .into_response());
+    }
 
-    state
+    let (version_name, effective_config) = state
         .launcher
-        .launch(
-            params.version.as_ref().map(|v| v.as_ref()),
-            user.0,
+        .effective_config(params.version.as_deref(), config.clone())
+        .map_err(|e| {
+            warn!("{}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let demand = Demand::new(&user.0, version_name, &effective_config);
+
+    let token = Uuid::new_v4().to_string();
+    let token_expires_at = state.token_expires_at();
+    if state.quotas.fits(&demand) {
+        if let Some(session) = state
+            .warm_pool
+            .claim(
+                &demand.version,
+                &user.0,
+                token.clone(),
+                token_expires_at,
+                config.clone(),
+                &effective_config,
+            )
+            .await
+            .map_err(internal_error)?
+        {
+            if let Err(reason) = state.quotas.admit(session.id, demand) {
+                // Another session took the capacity in the meantime
+                state
+                    .launcher
+                    .stop(session.id)
+                    .await
+                    .map_err(internal_error)?;
+                state
+                    .session_store
+                    .delete_session(&user.0, session.id)
+                    .await
+                    .map_err(internal_error)?;
+                state.launcher.remove_logs(session.id).await;
+                state.idle_sessions.forget(session.id);
+                return Ok((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Json(ErrorResponse { error: reason }),
+                )
+                    .into_response());
+            }
+            return Ok(Json(CreateSessionResponse {
+                id: session.id,
+                token,
+                token_expires_at,
+                state: session.state,
+                queue_position: None,
+            })
+            .into_response());
+        }
+    }
+
+    let session = state
+        .session_store
+        .create_session(
+            &user.0,
             token.clone(),
-            params.config.unwrap_or_default(),
+            token_expires_at,
+            params.version.clone(),
+            config.clone(),
         )
         .await
-        .map_err(|e| {
-            warn!("{:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(internal_error)?;
+
+    let mut queue_position = None;
+    if let Err(reason) = state.quotas.admit(session.id, demand.clone()) {
+        let queued = if state.quotas.is_queueing() {
+            state.quotas.enqueue(QueuedLaunch {
+                session_id: session.id,
+                demand,
+                config: config.clone(),
+            })
+        } else {
+            Err(reason)
+        };
+        match queued {
+            Ok(position) => {
+                info!("Queued session {} at position {}", session.id, position);
+                queue_position = Some(position);
+            }
+            Err(reason) => {
+                state
+                    .session_store
+                    .delete_session(&user.0, session.id)
+                    .await
+                    .map_err(internal_error)?;
+                return Ok((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Json(ErrorResponse { error: reason }),
+                )
+                    .into_response());
+            }
+        }
+    }
+
+    if queue_position.is_none() {
+        state
+            .launcher
+            .launch(
+                session.id,
+                params.version.as_ref().map(|v| v.as_ref()),
+                user.0.clone(),
+                config,
+            )
+            .await
+            .map_err(internal_error)?;
+    }
 
-    Ok(Json(CreateSessionResponse { token }))
+    let timeout = if wait.wait {
+        wait.timeout(&state)
+    } else {
+        Duration::ZERO
+    };
+    let session = wait_for_state(&state, &user.0, session.id, timeout).await?;
+
+    let status = if queue_position.is_some() && session.state == SessionState::Pending {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok((
+        status,
+        Json(CreateSessionResponse {
+            id: session.id,
+            token,
+            token_expires_at,
+            state: session.state,
+            queue_position,
+        }),
+    )
+        .into_response())
 }
 
 async fn get_session(
@@ -94,24 +416,311 @@ async fn get_session(
     let session = state
         .session_store
         .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
         .ok_or(StatusCode::NOT_FOUND)?;
 
     Ok(Json(session))
 }
 
+/// Long-poll until a session is ready. Responds with 504 if it's still starting once the
+/// timeout passes, or 409 if it stopped or failed instead.
+async fn wait_for_session(
+    State(state): State<AppStateDyn>,
+    Path(session_id): Path<u64>,
+    Extension(user): Extension<UserId>,
+    Query(wait): Query<WaitParams>,
+) -> Result<(StatusCode, Json<Session>), StatusCode> {
+    let session = wait_for_state(&state, &user.0, session_id, wait.timeout(&state)).await?;
+
+    let status = match session.state {
+        SessionState::Ready | SessionState::Idle => StatusCode::OK,
+        ref starting if starting.is_starting() => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::CONFLICT,
+    };
+    Ok((status, Json(session)))
+}
+
+#[derive(Serialize)]
+struct AppStatusResponse {
+    app_id: String,
+    // As reported by the cluster manager, if it could be understood
+    state: Option<String>,
+}
+
+/// Check on the application of a session submitted to a cluster manager. Responds with
+/// 404 until spark-submit has reported the application ID.
+async fn get_session_app(
+    State(state): State<AppStateDyn>,
+    Path(session_id): Path<u64>,
+    Extension(user): Extension<UserId>,
+) -> Result<Json<AppStatusResponse>, StatusCode> {
+    let session = state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let app_id = session.app_id.clone().ok_or(StatusCode::NOT_FOUND)?;
+
+    let app_state = state.launcher.status(&session).await.map_err(|e| {
+        warn!("{}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    Ok(Json(AppStatusResponse {
+        app_id,
+        state: app_state,
+    }))
+}
+
 async fn list_sessions(
     State(state): State<AppStateDyn>,
     Extension(user): Extension<UserId>,
-) -> Json<Vec<Session>> {
-    Json(state.session_store.list_sessions(&user.0))
+) -> Result<Json<Vec<Session>>, StatusCode> {
+    Ok(Json(
+        state
+            .session_store
+            .list_sessions(&user.0)
+            .await
+            .map_err(internal_error)?,
+    ))
 }
 
+/// Stop the session's driver and remove it, returning its final state. Responds with 409
+/// if the session is already being restarted or deleted.
 async fn delete_session(
     State(state): State<AppStateDyn>,
     Path(session_id): Path<u64>,
     Extension(user): Extension<UserId>,
-) {
-    state.session_store.delete_session(&user.0, session_id);
+) -> Result<Response, StatusCode> {
+    state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let Some(_busy) = state.busy_sessions.start(session_id) else {
+        return Ok(busy_response(session_id));
+    };
+
+    state
+        .launcher
+        .stop(session_id)
+        .await
+        .map_err(internal_error)?;
+
+    let session = state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    state
+        .session_store
+        .delete_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?;
+
+    state.launcher.remove_logs(session_id).await;
+    state.idle_sessions.forget(session_id);
+    state.upstreams.evict(session_id);
+
+    Ok(Json(session).into_response())
+}
+
+/// Stop the session's driver and launch it again with the same version and configs. The
+/// session keeps its ID and token, so clients reconnect through the proxy once the new
+/// driver is ready. Waits like creating a session does. Responds with 409 if the session
+/// was never launched, is being stopped, or is already being restarted or deleted, and 429
+/// if a finished session no longer fits within the quotas.
+async fn restart_session(
+    State(state): State<AppStateDyn>,
+    Path(session_id): Path<u64>,
+    Extension(user): Extension<UserId>,
+    Query(wait): Query<WaitParams>,
+) -> Result<Response, StatusCode> {
+    state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let Some(busy) = state.busy_sessions.start(session_id) else {
+        return Ok(busy_response(session_id));
+    };
+    // Looked up again now that nothing else can be changing it
+    let session = state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if matches!(
+        session.state,
+        SessionState::Pending | SessionState::Stopping
+    ) {
+        return Ok((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                error: format!("Session {} is {}", session_id, session.state.name()),
+            }),
+        )
+            .into_response());
+    }
+
+    // A session that's still running stays counted against the quotas while its driver
+    // restarts, a finished one has to fit again. Either is held until the new driver is
+    // launching, so it isn't released for the state it was in before
+    if !state.quotas.hold(session_id) {
+        let (version_name, effective_config) = state
+            .launcher
+            .effective_config(session.version.as_deref(), session.config.clone())
+            .map_err(internal_error)?;
+        let demand = Demand::new(&user.0, version_name, &effective_config);
+        if let Err(reason) = state.quotas.admit(session_id, demand) {
+            return Ok((
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(ErrorResponse { error: reason }),
+            )
+                .into_response());
+        }
+        // It's still finished until the new driver is launching, so it's held as well
+        state.quotas.hold(session_id);
+    }
+
+    info!("Restarting session {}", session_id);
+    let launched = restart_driver(&state, &user.0, &session).await;
+    state.quotas.unhold(session_id);
+    drop(busy);
+    launched?;
+
+    let timeout = if wait.wait {
+        wait.timeout(&state)
+    } else {
+        Duration::ZERO
+    };
+    let session = wait_for_state(&state, &user.0, session_id, timeout).await?;
+    Ok(Json(session).into_response())
+}
+
+async fn restart_driver(
+    state: &AppStateDyn,
+    username: &str,
+    session: &Session,
+) -> Result<(), StatusCode> {
+    state
+        .launcher
+        .stop(session.id)
+        .await
+        .map_err(internal_error)?;
+    // The new driver listens on a different address
+    state.upstreams.evict(session.id);
+
+    state
+        .launcher
+        .launch(
+            session.id,
+            session.version.as_deref(),
+            username.to_string(),
+            session.config.clone(),
+        )
+        .await
+        .map_err(internal_error)
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    id: u64,
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_expires_at: Option<u64>,
+}
+
+/// Replace a session's token with a new one, which also renews it if tokens expire. The
+/// old token stops working right away, while the driver keeps running.
+async fn rotate_session_token(
+    State(state): State<AppStateDyn>,
+    Path(session_id): Path<u64>,
+    Extension(user): Extension<UserId>,
+) -> Result<Json<TokenResponse>, StatusCode> {
+    state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let token = Uuid::new_v4().to_string();
+    let token_expires_at = state.token_expires_at();
+    state
+        .session_store
+        .set_session_token(session_id, token.clone(), token_expires_at)
+        .await
+        .map_err(internal_error)?;
+    state.upstreams.revoke(session_id);
+    info!("Rotated the token of session {}", session_id);
+
+    Ok(Json(TokenResponse {
+        id: session_id,
+        token,
+        token_expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SessionLogsParams {
+    // Only return this many of the most recent lines
+    tail: Option<usize>,
+    // Keep streaming new lines until the driver exits
+    #[serde(default)]
+    follow: bool,
+}
+
+async fn get_session_logs(
+    State(state): State<AppStateDyn>,
+    Path(session_id): Path<u64>,
+    Extension(user): Extension<UserId>,
+    Query(params): Query<SessionLogsParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    state
+        .session_store
+        .get_session(&user.0, session_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // Subscribe before reading the history so no lines are missed in between
+    let live = if params.follow {
+        state.launcher.follow_logs(session_id)
+    } else {
+        None
+    };
+
+    let lines = state
+        .launcher
+        .logs(session_id, params.tail)
+        .await
+        .map_err(internal_error)?;
+    let history = stream::iter(lines).map(|line| Ok::<_, Infallible>(format!("{}\n", line)));
+
+    let body = match live {
+        Some(live) => {
+            let live = stream::unfold(live, |mut live| async move {
+                use tokio::sync::broadcast::error::RecvError;
+                let line = match live.recv().await {
+                    Ok(line) => format!("{}\n", line),
+                    Err(RecvError::Lagged(skipped)) => format!("[{} lines skipped]\n", skipped),
+                    Err(RecvError::Closed) => return None,
+                };
+                Some((Ok(line), live))
+            });
+            Body::from_stream(history.chain(live))
+        }
+        None => Body::from_stream(history),
+    };
+
+    Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], body))
 }
 
 async fn list_versions(State(state): State<AppStateDyn>) -> Json<Vec<String>> {
@@ -128,9 +737,173 @@ async fn session_callback(
     Extension(token): Extension<BearerToken>,
     Json(params): Json<SessionCallbackRequest>,
 ) -> Result<(), StatusCode> {
-    info!("Got the callback for {}", token.0);
-    state
+    let session = state
+        .session_store
+        .get_session_by_driver_token(token.0.as_ref())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            warn!("Got a callback with an unknown token");
+            StatusCode::NOT_FOUND
+        })?;
+    info!(
+        "Got the callback for session {} at {}",
+        session.id, params.address
+    );
+
+    // A driver calling back for a session that already stopped or failed is rejected so
+    // it shuts itself down, and doesn't get to replace the session's address
+    if state
         .session_store
-        .set_session_addr(token.0.as_ref(), params.address);
-    Ok(())
+        .set_session_ready(session.id, params.address)
+        .await
+        .map_err(internal_error)?
+    {
+        // Idle time is counted from when the driver is ready
+        state.idle_sessions.touch(session.id);
+        Ok(())
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_launcher::FakeLauncher, store::InMemorySessionStore};
+
+    struct Harness {
+        state: AppStateDyn,
+        launcher: Arc<FakeLauncher>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let config = ProxyConfig::default();
+            let session_store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::default());
+            let launcher = Arc::new(FakeLauncher::new(
+                session_store.clone(),
+                Duration::from_millis(200),
+            ));
+            let idle_sessions = Arc::new(IdleSessions::from_config(&config));
+            let state = AppStateDyn {
+                session_store: session_store.clone(),
+                launcher: launcher.clone(),
+                quotas: Arc::new(Quotas::from_config(&config)),
+                warm_pool: Arc::new(WarmPool::from_config(
+                    &config,
+                    session_store,
+                    launcher.clone(),
+                    idle_sessions.clone(),
+                )),
+                idle_sessions: idle_sessions.clone(),
+                upstreams: Arc::new(UpstreamPool::new(idle_sessions)),
+                busy_sessions: Arc::new(BusySessions::default()),
+                ready_timeout: Duration::ZERO,
+                token_ttl: None,
+            };
+            Self { state, launcher }
+        }
+
+        async fn ready_session(&self) -> u64 {
+            let session_store = &self.state.session_store;
+            let session = session_store
+                .create_session("alice", "token".to_string(), None, None, HashMap::new())
+                .await
+                .unwrap();
+            for state in [SessionState::Launching, SessionState::Ready] {
+                session_store
+                    .set_session_state(session.id, state)
+                    .await
+                    .unwrap();
+            }
+            session.id
+        }
+
+        async fn restart(&self, session_id: u64) -> StatusCode {
+            restart_session(
+                State(self.state.clone()),
+                Path(session_id),
+                Extension(UserId("alice".to_string())),
+                Query(WaitParams {
+                    wait: false,
+                    timeout: None,
+                }),
+            )
+            .await
+            .map_or_else(|status| status, |response| response.status())
+        }
+
+        async fn delete(&self, session_id: u64) -> StatusCode {
+            delete_session(
+                State(self.state.clone()),
+                Path(session_id),
+                Extension(UserId("alice".to_string())),
+            )
+            .await
+            .map_or_else(|status| status, |response| response.status())
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_one_at_a_time() {
+        let harness = Harness::new();
+        let session_id = harness.ready_session().await;
+
+        let (first, second) =
+            tokio::join!(harness.restart(session_id), harness.restart(session_id));
+        let mut statuses = [first, second];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+        assert_eq!(*harness.launcher.launched.lock().unwrap(), vec![session_id]);
+
+        // Once it's done, it can be restarted again
+        assert_eq!(harness.restart(session_id).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn doesnt_restart_sessions_being_deleted() {
+        let harness = Harness::new();
+        let session_id = harness.ready_session().await;
+
+        let (deleted, restarted) =
+            tokio::join!(harness.delete(session_id), harness.restart(session_id));
+        assert_eq!(deleted, StatusCode::OK);
+        assert_eq!(restarted, StatusCode::CONFLICT);
+        assert!(harness.launcher.launched.lock().unwrap().is_empty());
+        assert!(harness
+            .state
+            .session_store
+            .get_session_by_id(session_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn late_callbacks_dont_replace_the_address() {
+        let harness = Harness::new();
+        let session_store = &harness.state.session_store;
+        let session_id = harness.ready_session().await;
+        session_store
+            .set_session_driver_token(session_id, "driver".to_string())
+            .await
+            .unwrap();
+        session_store
+            .set_session_state(session_id, SessionState::Stopping)
+            .await
+            .unwrap();
+
+        let result = session_callback(
+            State(harness.state.clone()),
+            Extension(BearerToken("driver".to_string())),
+            Json(SessionCallbackRequest {
+                address: "127.0.0.1:15002".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(result, Err(StatusCode::CONFLICT));
+        let session = session_store.get_session_by_id(session_id).await.unwrap();
+        assert_eq!(session.unwrap().addr, None);
+    }
 }
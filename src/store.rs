@@ -1,103 +1,986 @@
 use std::{
     collections::HashMap,
+    error::Error,
+    future::Future,
     sync::{atomic::AtomicU64, Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use serde::Serialize;
+use async_trait::async_trait;
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::TcpStream,
+    sync::{watch, RwLock},
+    time::Instant,
+};
+
+// How long to wait for a persisted driver to accept a connection on startup
+const RECOVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type StoreResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SessionState {
+    // Created but the driver hasn't been started yet
+    Pending,
+    // The driver has been started and we're waiting on its callback
+    Launching,
+    // The driver called back and is accepting connections
+    Ready,
+    // No calls were made for longer than the idle timeout, and the driver is being stopped
+    Idle,
+    Stopping,
+    Stopped,
+    Failed { reason: String },
+}
+
+impl SessionState {
+    /// The name the state is serialized with
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionState::Pending => "pending",
+            SessionState::Launching => "launching",
+            SessionState::Ready => "ready",
+            SessionState::Idle => "idle",
+            SessionState::Stopping => "stopping",
+            SessionState::Stopped => "stopped",
+            SessionState::Failed { .. } => "failed",
+        }
+    }
+
+    /// Whether the session hasn't finished starting up yet
+    pub fn is_starting(&self) -> bool {
+        matches!(self, SessionState::Pending | SessionState::Launching)
+    }
+
+    /// Whether the session's driver is done for good, unless it's relaunched
+    pub fn is_finished(&self) -> bool {
+        matches!(self, SessionState::Stopped | SessionState::Failed { .. })
+    }
+
+    /// Whether a session in this state is allowed to move to `next`
+    pub fn can_transition_to(&self, next: &SessionState) -> bool {
+        use SessionState::*;
+        match (self, next) {
+            // Finished sessions can only be relaunched
+            (Stopped | Failed { .. }, Launching) => true,
+            (Stopped | Failed { .. }, _) => false,
+            (Stopping, Stopped | Failed { .. }) => true,
+            (Stopping, _) => false,
+            (_, Stopping | Stopped | Failed { .. }) => true,
+            (Pending, Launching) | (Launching, Ready) | (Ready, Idle) | (Idle, Ready) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The local process group of a running driver, identified by its root process's start
+/// time as well as its ID, since the ID may be reused once the driver exits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DriverProcess {
+    pub pgid: i32,
+    // In clock ticks since boot, as in /proc/<pid>/stat
+    pub start_time: u64,
+}
 
 #[derive(Clone, Serialize)]
 pub struct Session {
     pub id: u64,
     pub addr: Option<String>,
-    pub token: String,
+    #[serde(flatten)]
+    pub state: SessionState,
+    // Exit code of the most recent driver process, if it exited normally
+    pub exit_code: Option<i32>,
+    // ID the cluster manager gave the driver's application, once it's known
+    pub app_id: Option<String>,
+    // Stores that only keep a hash of the token can't return it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    // Unix timestamp in seconds after which the token is no longer accepted
+    pub token_expires_at: Option<u64>,
+    // Token the driver was launched with, which the proxy presents to it and which alone
+    // authenticates its callback. Unset until the driver is first launched
+    #[serde(skip_serializing)]
+    pub driver_token: Option<String>,
+    // The driver's local process while it's running, so a driver that outlived a restart
+    // of the proxy can still be stopped
+    #[serde(skip_serializing)]
+    pub driver_process: Option<DriverProcess>,
+    pub version: Option<String>,
+    pub config: HashMap<String, String>,
+    // Unix timestamp in seconds
+    pub created_at: u64,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl Session {
+    pub fn is_token_expired(&self) -> bool {
+        self.token_expires_at
+            .is_some_and(|expires_at| now() >= expires_at)
+    }
 }
 
-// #[async_trait]
+#[async_trait]
 pub trait SessionStore: Send + Sync {
-    fn create_session(&self, username: &str, token: String);
+    async fn create_session(
+        &self,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        version: Option<String>,
+        config: HashMap<String, String>,
+    ) -> StoreResult<Session>;
 
-    fn get_session(&self, username: &str, id: u64) -> Option<Session>;
+    async fn get_session(&self, username: &str, id: u64) -> StoreResult<Option<Session>>;
 
-    fn get_session_by_token(&self, token: &str) -> Option<Session>;
+    async fn get_session_by_token(&self, token: &str) -> StoreResult<Option<Session>>;
 
-    fn set_session_addr(&self, token: &str, addr: String);
+    /// Look up a session whichever user it belongs to
+    async fn get_session_by_id(&self, id: u64) -> StoreResult<Option<Session>>;
 
-    fn list_sessions(&self, username: &str) -> Vec<Session>;
+    /// Look up the session whose driver was launched with `token`
+    async fn get_session_by_driver_token(&self, token: &str) -> StoreResult<Option<Session>>;
 
-    fn delete_session(&self, username: &str, id: u64);
+    /// Move a session to ready along with the address its driver listens on. Returns
+    /// `false` without recording the address if the session can't become ready.
+    async fn set_session_ready(&self, id: u64, addr: String) -> StoreResult<bool>;
+
+    /// Hand a session and its running driver over to another user under a new token.
+    /// Returns `false` if the session doesn't belong to `from_username` anymore.
+    async fn assign_session(
+        &self,
+        id: u64,
+        from_username: &str,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        config: HashMap<String, String>,
+    ) -> StoreResult<bool>;
+
+    /// Replace a session's token. Its driver keeps the token it was launched with.
+    async fn set_session_token(
+        &self,
+        id: u64,
+        token: String,
+        token_expires_at: Option<u64>,
+    ) -> StoreResult<()>;
+
+    /// Move a session to a new state. Transitions the current state doesn't allow are
+    /// ignored, and `false` is returned.
+    async fn set_session_state(&self, id: u64, state: SessionState) -> StoreResult<bool>;
+
+    async fn set_session_exit_code(&self, id: u64, exit_code: Option<i32>) -> StoreResult<()>;
+
+    async fn set_session_app_id(&self, id: u64, app_id: String) -> StoreResult<()>;
+
+    /// Record the token a session's driver is launched with
+    async fn set_session_driver_token(&self, id: u64, driver_token: String) -> StoreResult<()>;
+
+    /// Record the process of a session's running driver, or clear it once it exits
+    async fn set_session_driver_process(
+        &self,
+        id: u64,
+        driver_process: Option<DriverProcess>,
+    ) -> StoreResult<()>;
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>>;
+
+    /// Every session that hasn't stopped or failed, with the user it belongs to, in the
+    /// order they were created
+    async fn list_unfinished_sessions(&self) -> StoreResult<Vec<(String, Session)>>;
+
+    async fn delete_session(&self, username: &str, id: u64) -> StoreResult<()>;
+
+    /// Notified whenever any session changes state or is deleted
+    fn state_changes(&self) -> watch::Receiver<()>;
+}
+
+/// Wait until the session returned by `lookup` has finished starting up, or `timeout`
+/// passes. Returns the last version of the session seen.
+pub async fn wait_until_started<F, Fut>(
+    session_store: &dyn SessionStore,
+    timeout: Duration,
+    lookup: F,
+) -> StoreResult<Option<Session>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = StoreResult<Option<Session>>>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut changes = session_store.state_changes();
+    loop {
+        // Mark the current state seen before looking, so no change in between is missed
+        changes.borrow_and_update();
+        let session = lookup().await?;
+        if !session
+            .as_ref()
+            .is_some_and(|session| session.state.is_starting())
+        {
+            return Ok(session);
+        }
+        match tokio::time::timeout_at(deadline, changes.changed()).await {
+            Ok(Ok(())) => (),
+            _ => return Ok(session),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct InMemorySessionStore {
-    sessions: Arc<Mutex<HashMap<String, HashMap<u64, Session>>>>,
+    sessions: RwLock<HashMap<String, HashMap<u64, Session>>>,
     next_session_id: AtomicU64,
+    state_changes: watch::Sender<()>,
 }
 
-// #[async_trait]
+#[async_trait]
 impl SessionStore for InMemorySessionStore {
-    fn create_session(&self, username: &str, token: String) {
+    async fn create_session(
+        &self,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        version: Option<String>,
+        config: HashMap<String, String>,
+    ) -> StoreResult<Session> {
         let id = self
             .next_session_id
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let session = Session {
+            id,
+            addr: None,
+            state: SessionState::Pending,
+            exit_code: None,
+            app_id: None,
+            token: Some(token),
+            token_expires_at,
+            driver_token: None,
+            driver_process: None,
+            version,
+            config,
+            created_at: now(),
+        };
         self.sessions
-            .lock()
-            .unwrap()
+            .write()
+            .await
             .entry(username.to_string())
             .or_default()
-            .insert(
-                id,
-                Session {
-                    id,
-                    addr: None,
-                    token,
-                },
-            );
+            .insert(id, session.clone());
+        Ok(session)
     }
 
-    fn get_session(&self, username: &str, id: u64) -> Option<Session> {
-        self.sessions
-            .lock()
-            .unwrap()
+    async fn get_session(&self, username: &str, id: u64) -> StoreResult<Option<Session>> {
+        Ok(self
+            .sessions
+            .read()
+            .await
             .get(username)
             .and_then(|sessions| sessions.get(&id))
-            .cloned()
+            .cloned())
     }
 
-    fn get_session_by_token(&self, token: &str) -> Option<Session> {
-        self.sessions
-            .lock()
-            .unwrap()
+    async fn get_session_by_token(&self, token: &str) -> StoreResult<Option<Session>> {
+        Ok(self
+            .sessions
+            .read()
+            .await
+            .values()
+            .flat_map(|sessions| sessions.values())
+            .find(|session| session.token.as_deref() == Some(token))
+            .cloned())
+    }
+
+    async fn get_session_by_id(&self, id: u64) -> StoreResult<Option<Session>> {
+        Ok(self
+            .sessions
+            .read()
+            .await
+            .values()
+            .find_map(|sessions| sessions.get(&id))
+            .cloned())
+    }
+
+    async fn get_session_by_driver_token(&self, token: &str) -> StoreResult<Option<Session>> {
+        Ok(self
+            .sessions
+            .read()
+            .await
             .values()
             .flat_map(|sessions| sessions.values())
-            .find(|session| session.token == token)
-            .cloned()
+            .find(|session| session.driver_token.as_deref() == Some(token))
+            .cloned())
     }
 
-    fn set_session_addr(&self, token: &str, addr: String) {
+    async fn set_session_ready(&self, id: u64, addr: String) -> StoreResult<bool> {
         if let Some(session) = self
             .sessions
-            .lock()
-            .unwrap()
+            .write()
+            .await
             .values_mut()
-            .flat_map(|sessions| sessions.values_mut())
-            .find(|session| session.token == token)
+            .find_map(|sessions| sessions.get_mut(&id))
         {
-            session.addr = Some(addr)
+            if session.state.can_transition_to(&SessionState::Ready) {
+                session.addr = Some(addr);
+                session.state = SessionState::Ready;
+                self.state_changes.send_replace(());
+                return Ok(true);
+            }
         }
+        Ok(false)
     }
 
-    fn list_sessions(&self, username: &str) -> Vec<Session> {
-        self.sessions
-            .lock()
-            .unwrap()
+    async fn assign_session(
+        &self,
+        id: u64,
+        from_username: &str,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        config: HashMap<String, String>,
+    ) -> StoreResult<bool> {
+        let mut sessions = self.sessions.write().await;
+        let Some(mut session) = sessions
+            .get_mut(from_username)
+            .and_then(|sessions| sessions.remove(&id))
+        else {
+            return Ok(false);
+        };
+        session.token = Some(token);
+        session.token_expires_at = token_expires_at;
+        session.config = config;
+        sessions
+            .entry(username.to_string())
+            .or_default()
+            .insert(id, session);
+        Ok(true)
+    }
+
+    async fn set_session_token(
+        &self,
+        id: u64,
+        token: String,
+        token_expires_at: Option<u64>,
+    ) -> StoreResult<()> {
+        if let Some(session) = self
+            .sessions
+            .write()
+            .await
+            .values_mut()
+            .find_map(|sessions| sessions.get_mut(&id))
+        {
+            session.token = Some(token);
+            session.token_expires_at = token_expires_at;
+        }
+        Ok(())
+    }
+
+    async fn set_session_state(&self, id: u64, state: SessionState) -> StoreResult<bool> {
+        if let Some(session) = self
+            .sessions
+            .write()
+            .await
+            .values_mut()
+            .find_map(|sessions| sessions.get_mut(&id))
+        {
+            if session.state.can_transition_to(&state) {
+                session.state = state;
+                self.state_changes.send_replace(());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn set_session_exit_code(&self, id: u64, exit_code: Option<i32>) -> StoreResult<()> {
+        if let Some(session) = self
+            .sessions
+            .write()
+            .await
+            .values_mut()
+            .find_map(|sessions| sessions.get_mut(&id))
+        {
+            session.exit_code = exit_code;
+        }
+        Ok(())
+    }
+
+    async fn set_session_app_id(&self, id: u64, app_id: String) -> StoreResult<()> {
+        if let Some(session) = self
+            .sessions
+            .write()
+            .await
+            .values_mut()
+            .find_map(|sessions| sessions.get_mut(&id))
+        {
+            session.app_id = Some(app_id);
+        }
+        Ok(())
+    }
+
+    async fn set_session_driver_token(&self, id: u64, driver_token: String) -> StoreResult<()> {
+        if let Some(session) = self
+            .sessions
+            .write()
+            .await
+            .values_mut()
+            .find_map(|sessions| sessions.get_mut(&id))
+        {
+            session.driver_token = Some(driver_token);
+        }
+        Ok(())
+    }
+
+    async fn set_session_driver_process(
+        &self,
+        id: u64,
+        driver_process: Option<DriverProcess>,
+    ) -> StoreResult<()> {
+        if let Some(session) = self
+            .sessions
+            .write()
+            .await
+            .values_mut()
+            .find_map(|sessions| sessions.get_mut(&id))
+        {
+            session.driver_process = driver_process;
+        }
+        Ok(())
+    }
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>> {
+        Ok(self
+            .sessions
+            .read()
+            .await
             .get(username)
             .map(|sessions| sessions.values().cloned().collect())
-            .unwrap_or_default()
+            .unwrap_or_default())
     }
 
-    fn delete_session(&self, username: &str, id: u64) {
-        if let Some(sessions) = self.sessions.lock().unwrap().get_mut(username) {
+    async fn list_unfinished_sessions(&self) -> StoreResult<Vec<(String, Session)>> {
+        let mut sessions: Vec<(String, Session)> = self
+            .sessions
+            .read()
+            .await
+            .iter()
+            .flat_map(|(username, sessions)| {
+                sessions
+                    .values()
+                    .filter(|session| !session.state.is_finished())
+                    .map(|session| (username.clone(), session.clone()))
+            })
+            .collect();
+        sessions.sort_by_key(|(_, session)| session.id);
+        Ok(sessions)
+    }
+
+    async fn delete_session(&self, username: &str, id: u64) -> StoreResult<()> {
+        if let Some(sessions) = self.sessions.write().await.get_mut(username) {
             sessions.remove(&id);
         }
+        self.state_changes.send_replace(());
+        Ok(())
+    }
+
+    fn state_changes(&self) -> watch::Receiver<()> {
+        self.state_changes.subscribe()
+    }
+}
+
+/// Session store persisted to SQLite so sessions survive a proxy restart. Only a SHA-256
+/// hash of each session token is stored. Driver tokens are stored as is, since the proxy
+/// has to present them to the drivers.
+pub struct SqliteSessionStore {
+    conn: Arc<Mutex<Connection>>,
+    state_changes: watch::Sender<()>,
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn session_from_row(row: &Row) -> rusqlite::Result<Session> {
+    let config: String = row.get("config")?;
+    let state: String = row.get("state")?;
+    Ok(Session {
+        id: row.get("id")?,
+        addr: row.get("addr")?,
+        state: serde_json::from_str(&state).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+        })?,
+        exit_code: row.get("exit_code")?,
+        app_id: row.get("app_id")?,
+        token: None,
+        token_expires_at: row.get("token_expires_at")?,
+        driver_token: row.get("driver_token")?,
+        driver_process: match (row.get("driver_pgid")?, row.get("driver_start_time")?) {
+            (Some(pgid), Some(start_time)) => Some(DriverProcess { pgid, start_time }),
+            _ => None,
+        },
+        version: row.get("version")?,
+        config: serde_json::from_str(&config).unwrap_or_default(),
+        created_at: row.get("created_at")?,
+    })
+}
+
+impl SqliteSessionStore {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                addr TEXT,
+                state TEXT NOT NULL,
+                exit_code INTEGER,
+                app_id TEXT,
+                token_expires_at INTEGER,
+                driver_token TEXT,
+                driver_pgid INTEGER,
+                driver_start_time INTEGER,
+                version TEXT,
+                config TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_username ON sessions (username);",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            state_changes: watch::Sender::default(),
+        })
+    }
+
+    /// Run a query on a blocking thread so SQLite IO doesn't stall the runtime
+    async fn with_conn<T, F>(&self, f: F) -> StoreResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&conn.lock().unwrap())).await??)
+    }
+
+    /// Check which persisted drivers survived a restart. Sessions whose driver no longer
+    /// accepts connections are failed. Sessions that weren't serving are left to the
+    /// launcher.
+    pub async fn recover(&self) -> StoreResult<()> {
+        let sessions = self
+            .with_conn(|conn| {
+                conn.prepare("SELECT id, addr, state FROM sessions WHERE addr IS NOT NULL")?
+                    .query_map([], |row| {
+                        Ok((
+                            row.get::<_, u64>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                        ))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        for (id, addr, state) in sessions {
+            // Only sessions that were serving before the restart can be reconnected
+            let state: SessionState = serde_json::from_str(&state)?;
+            if !matches!(state, SessionState::Ready | SessionState::Idle) {
+                continue;
+            }
+            match tokio::time::timeout(RECOVER_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+                Ok(Ok(_)) => info!("Reconnected to session {} at {}", id, addr),
+                _ => {
+                    warn!("Driver for session {} at {} is gone", id, addr);
+                    self.set_session_state(
+                        id,
+                        SessionState::Failed {
+                            reason: "Driver was lost while the proxy was down".to_string(),
+                        },
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn create_session(
+        &self,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        version: Option<String>,
+        config: HashMap<String, String>,
+    ) -> StoreResult<Session> {
+        let username = username.to_string();
+        let mut session = Session {
+            id: 0,
+            addr: None,
+            state: SessionState::Pending,
+            exit_code: None,
+            app_id: None,
+            token: None,
+            token_expires_at,
+            driver_token: None,
+            driver_process: None,
+            version,
+            config,
+            created_at: now(),
+        };
+        let state = serde_json::to_string(&session.state)?;
+        let config = serde_json::to_string(&session.config)?;
+        let version = session.version.clone();
+        let created_at = session.created_at;
+        session.id = self
+            .with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessions
+                        (username, token_hash, token_expires_at, state, version, config, created_at)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        username,
+                        hash_token(&token),
+                        token_expires_at,
+                        state,
+                        version,
+                        config,
+                        created_at
+                    ],
+                )?;
+                Ok(conn.last_insert_rowid() as u64)
+            })
+            .await?;
+        Ok(session)
+    }
+
+    async fn get_session(&self, username: &str, id: u64) -> StoreResult<Option<Session>> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT * FROM sessions WHERE username = ?1 AND id = ?2",
+                params![username, id],
+                session_from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn get_session_by_token(&self, token: &str) -> StoreResult<Option<Session>> {
+        let token_hash = hash_token(token);
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT * FROM sessions WHERE token_hash = ?1",
+                [token_hash],
+                session_from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn get_session_by_id(&self, id: u64) -> StoreResult<Option<Session>> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT * FROM sessions WHERE id = ?1",
+                [id],
+                session_from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn get_session_by_driver_token(&self, token: &str) -> StoreResult<Option<Session>> {
+        let token = token.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT * FROM sessions WHERE driver_token = ?1",
+                params![token],
+                session_from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn set_session_ready(&self, id: u64, addr: String) -> StoreResult<bool> {
+        let ready = self
+            .with_conn(move |conn| {
+                let current: Option<String> = conn
+                    .query_row("SELECT state FROM sessions WHERE id = ?1", [id], |row| {
+                        row.get(0)
+                    })
+                    .optional()?;
+                let allowed = current
+                    .and_then(|current| serde_json::from_str::<SessionState>(&current).ok())
+                    .is_some_and(|current| current.can_transition_to(&SessionState::Ready));
+                if allowed {
+                    conn.execute(
+                        "UPDATE sessions SET addr = ?1, state = ?2 WHERE id = ?3",
+                        params![
+                            addr,
+                            serde_json::to_string(&SessionState::Ready).unwrap(),
+                            id
+                        ],
+                    )?;
+                }
+                Ok(allowed)
+            })
+            .await?;
+        if ready {
+            self.state_changes.send_replace(());
+        }
+        Ok(ready)
+    }
+
+    async fn assign_session(
+        &self,
+        id: u64,
+        from_username: &str,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        config: HashMap<String, String>,
+    ) -> StoreResult<bool> {
+        let from_username = from_username.to_string();
+        let username = username.to_string();
+        let config = serde_json::to_string(&config)?;
+        self.with_conn(move |conn| {
+            let updated = conn.execute(
+                "UPDATE sessions
+                    SET username = ?1, token_hash = ?2, token_expires_at = ?3, config = ?4
+                    WHERE id = ?5 AND username = ?6",
+                params![
+                    username,
+                    hash_token(&token),
+                    token_expires_at,
+                    config,
+                    id,
+                    from_username
+                ],
+            )?;
+            Ok(updated == 1)
+        })
+        .await
+    }
+
+    async fn set_session_token(
+        &self,
+        id: u64,
+        token: String,
+        token_expires_at: Option<u64>,
+    ) -> StoreResult<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET token_hash = ?1, token_expires_at = ?2 WHERE id = ?3",
+                params![hash_token(&token), token_expires_at, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_session_state(&self, id: u64, state: SessionState) -> StoreResult<bool> {
+        let allowed = self
+            .with_conn(move |conn| {
+                let current: Option<String> = conn
+                    .query_row("SELECT state FROM sessions WHERE id = ?1", [id], |row| {
+                        row.get(0)
+                    })
+                    .optional()?;
+                let allowed = current
+                    .and_then(|current| serde_json::from_str::<SessionState>(&current).ok())
+                    .is_some_and(|current| current.can_transition_to(&state));
+                if allowed {
+                    conn.execute(
+                        "UPDATE sessions SET state = ?1 WHERE id = ?2",
+                        params![serde_json::to_string(&state).unwrap(), id],
+                    )?;
+                }
+                Ok(allowed)
+            })
+            .await?;
+        if allowed {
+            self.state_changes.send_replace(());
+        }
+        Ok(allowed)
+    }
+
+    async fn set_session_exit_code(&self, id: u64, exit_code: Option<i32>) -> StoreResult<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET exit_code = ?1 WHERE id = ?2",
+                params![exit_code, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_session_app_id(&self, id: u64, app_id: String) -> StoreResult<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET app_id = ?1 WHERE id = ?2",
+                params![app_id, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_session_driver_token(&self, id: u64, driver_token: String) -> StoreResult<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET driver_token = ?1 WHERE id = ?2",
+                params![driver_token, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_session_driver_process(
+        &self,
+        id: u64,
+        driver_process: Option<DriverProcess>,
+    ) -> StoreResult<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET driver_pgid = ?1, driver_start_time = ?2 WHERE id = ?3",
+                params![
+                    driver_process.map(|process| process.pgid),
+                    driver_process.map(|process| process.start_time),
+                    id
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.prepare("SELECT * FROM sessions WHERE username = ?1 ORDER BY id")?
+                .query_map([username], session_from_row)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn list_unfinished_sessions(&self) -> StoreResult<Vec<(String, Session)>> {
+        let sessions: Vec<(String, Session)> = self
+            .with_conn(|conn| {
+                conn.prepare("SELECT * FROM sessions ORDER BY id")?
+                    .query_map([], |row| Ok((row.get("username")?, session_from_row(row)?)))?
+                    .collect()
+            })
+            .await?;
+        Ok(sessions
+            .into_iter()
+            .filter(|(_, session)| !session.state.is_finished())
+            .collect())
+    }
+
+    async fn delete_session(&self, username: &str, id: u64) -> StoreResult<()> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM sessions WHERE username = ?1 AND id = ?2",
+                params![username, id],
+            )
+        })
+        .await?;
+        self.state_changes.send_replace(());
+        Ok(())
+    }
+
+    fn state_changes(&self) -> watch::Receiver<()> {
+        self.state_changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_unfinished_sessions_of_every_user() {
+        let path = env::temp_dir().join(format!("spark-connect-proxy-{}.db", Uuid::new_v4()));
+        let store = SqliteSessionStore::open(path.to_str().unwrap()).unwrap();
+        for (username, token) in [("alice", "a"), ("bob", "b"), ("bob", "c")] {
+            store
+                .create_session(username, token.to_string(), None, None, HashMap::new())
+                .await
+                .unwrap();
+        }
+        store
+            .set_session_state(2, SessionState::Stopped)
+            .await
+            .unwrap();
+
+        let unfinished: Vec<(String, u64)> = store
+            .list_unfinished_sessions()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(username, session)| (username, session.id))
+            .collect();
+        assert_eq!(
+            unfinished,
+            vec![("alice".to_string(), 1), ("bob".to_string(), 3)]
+        );
+
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_driver_tokens_find_sessions_by_driver_token() {
+        let path = env::temp_dir().join(format!("spark-connect-proxy-{}.db", Uuid::new_v4()));
+        let stores: [Box<dyn SessionStore>; 2] = [
+            Box::new(InMemorySessionStore::default()),
+            Box::new(SqliteSessionStore::open(path.to_str().unwrap()).unwrap()),
+        ];
+        for store in stores {
+            let session = store
+                .create_session("alice", "session".to_string(), None, None, HashMap::new())
+                .await
+                .unwrap();
+            // Not even before the driver is launched
+            assert!(store
+                .get_session_by_driver_token("session")
+                .await
+                .unwrap()
+                .is_none());
+
+            store
+                .set_session_driver_token(session.id, "driver".to_string())
+                .await
+                .unwrap();
+            assert!(store
+                .get_session_by_driver_token("session")
+                .await
+                .unwrap()
+                .is_none());
+            let found = store.get_session_by_driver_token("driver").await.unwrap();
+            assert_eq!(found.map(|found| found.id), Some(session.id));
+        }
+        fs::remove_file(&path).unwrap();
     }
 }
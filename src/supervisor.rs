@@ -0,0 +1,596 @@
+/// Module for supervising launched Spark driver processes
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io,
+    path::PathBuf,
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::{getgrouplist, setgid, setgroups, setuid, Pid, User},
+};
+use tokio::{
+    process::{Child, Command},
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+};
+
+use crate::{
+    cluster::ClusterApp,
+    config::{RestartOn, RestartPolicy},
+    logs::DriverLogs,
+    store::{DriverProcess, Session, SessionState, SessionStore},
+};
+
+const DEFAULT_RESTART_BACKOFF: u64 = 5;
+// How often a recovered driver being stopped is checked for having exited
+const RECOVERED_EXIT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Everything needed to (re)start a driver process
+#[derive(Clone, Debug)]
+pub struct DriverCommand {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub envs: HashMap<String, String>,
+    // The cluster manager the driver is submitted to, if it isn't local
+    pub cluster: Option<ClusterApp>,
+    // Local account the driver runs as instead of the proxy's
+    pub user: Option<User>,
+    // File the driver reads its token from, removed once the driver is done for good
+    pub token_file: Option<PathBuf>,
+}
+
+impl DriverCommand {
+    fn spawn(&self) -> Result<Child, io::Error> {
+        info!("Running {:?} {}", self.program, self.args.join(" "));
+
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .envs(&self.envs)
+            // Run in a new process group so the whole driver tree can be signalled
+            .process_group(0)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(user) = self.user.as_ref() {
+            // Looked up ahead of time, since only async-signal-safe calls can be made
+            // between fork and exec
+            let name = CString::new(user.name.as_str())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let groups = getgrouplist(&name, user.gid)?;
+            let (uid, gid) = (user.uid, user.gid);
+            command
+                .env("HOME", &user.dir)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name);
+            // The user's supplementary groups can only be set while still privileged, and
+            // `uid` would drop privileges before any hook runs, so the user is switched to
+            // here instead
+            unsafe {
+                command.pre_exec(move || {
+                    setgroups(&groups)?;
+                    setgid(gid)?;
+                    setuid(uid)?;
+                    Ok(())
+                });
+            }
+        }
+        command.spawn()
+    }
+}
+
+/// A running driver process and the lines it writes
+struct Running {
+    child: Child,
+    lines: broadcast::Receiver<String>,
+}
+
+/// A supervised driver process
+#[derive(Clone)]
+struct DriverHandle {
+    // Process group of the current driver process, which is the pid of its root process
+    pgid: Option<Pid>,
+    // Flips to true once the driver is done for good and its final state is recorded
+    exited: watch::Receiver<bool>,
+    // Flipped to true when the driver is being stopped, so it isn't restarted
+    stop: Arc<watch::Sender<bool>>,
+    cluster: Option<ClusterApp>,
+    // Application of the current driver, once spark-submit has reported it
+    app_id: Option<String>,
+}
+
+/// Owns every driver process. Records how each one exits, and restarts them according
+/// to their restart policy.
+pub struct Supervisor {
+    session_store: Arc<dyn SessionStore>,
+    driver_logs: Arc<DriverLogs>,
+    // Supervised drivers by session ID
+    drivers: Mutex<HashMap<u64, DriverHandle>>,
+    stop_grace_period: Duration,
+}
+
+impl Supervisor {
+    pub fn new(
+        session_store: Arc<dyn SessionStore>,
+        driver_logs: Arc<DriverLogs>,
+        stop_grace_period: Duration,
+    ) -> Self {
+        Self {
+            session_store,
+            driver_logs,
+            drivers: Mutex::new(HashMap::new()),
+            stop_grace_period,
+        }
+    }
+
+    /// Start a driver for a session and supervise it until it stops for good
+    pub fn supervise(
+        self: &Arc<Self>,
+        session_id: u64,
+        command: DriverCommand,
+        restart_policy: Option<RestartPolicy>,
+    ) -> Result<(), io::Error> {
+        let mut child = command.spawn()?;
+        let lines = self.driver_logs.capture(session_id, &mut child, true);
+
+        let (exited_tx, exited_rx) = watch::channel(false);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        self.drivers.lock().unwrap().insert(
+            session_id,
+            DriverHandle {
+                pgid: pid_of(&child),
+                exited: exited_rx,
+                stop: Arc::new(stop_tx),
+                cluster: command.cluster.clone(),
+                app_id: None,
+            },
+        );
+
+        let supervisor = self.clone();
+        let token_file = command.token_file.clone();
+        let pgid = pid_of(&child);
+        tokio::task::spawn(async move {
+            supervisor.record_process(session_id, pgid).await;
+            supervisor
+                .run(
+                    session_id,
+                    command,
+                    restart_policy,
+                    Running { child, lines },
+                    stop_rx,
+                )
+                .await;
+            supervisor.record_process(session_id, None).await;
+            supervisor.drivers.lock().unwrap().remove(&session_id);
+            supervisor.driver_logs.finish(session_id);
+            if let Some(token_file) = token_file {
+                if let Err(e) = tokio::fs::remove_file(&token_file).await {
+                    warn!("Failed to remove token file {:?}: {}", token_file, e);
+                }
+            }
+            let _ = exited_tx.send(true);
+        });
+        Ok(())
+    }
+
+    async fn run(
+        &self,
+        session_id: u64,
+        command: DriverCommand,
+        restart_policy: Option<RestartPolicy>,
+        mut running: Running,
+        mut stop: watch::Receiver<bool>,
+    ) {
+        let mut restarts = 0;
+        loop {
+            let status = self.wait_for_exit(session_id, &command, &mut running).await;
+            info!("Driver for session {} exited: {:?}", session_id, status);
+
+            let Some(state) = self.record_exit(session_id, status).await else {
+                return;
+            };
+
+            let Some(policy) = restart_policy.as_ref() else {
+                return;
+            };
+            let restart = match (&policy.restart_on, &state) {
+                _ if *stop.borrow() => false,
+                (RestartOn::Exit, _) => true,
+                (RestartOn::Failure, SessionState::Failed { .. }) => true,
+                _ => false,
+            };
+            if !restart || restarts >= policy.max_restarts {
+                return;
+            }
+            restarts += 1;
+
+            let backoff = Duration::from_secs(policy.backoff.unwrap_or(DEFAULT_RESTART_BACKOFF));
+            info!(
+                "Restarting driver for session {} in {:?} ({}/{})",
+                session_id, backoff, restarts, policy.max_restarts
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => (),
+                _ = stop.wait_for(|stop| *stop) => return,
+            }
+
+            // The session may have been deleted or stopped while we waited
+            if !self.set_state(session_id, SessionState::Launching).await {
+                return;
+            }
+
+            running = match command.spawn() {
+                Ok(mut child) => {
+                    let lines = self.driver_logs.capture(session_id, &mut child, false);
+                    Running { child, lines }
+                }
+                Err(e) => {
+                    self.set_state(
+                        session_id,
+                        SessionState::Failed {
+                            reason: format!("Failed to restart driver: {}", e),
+                        },
+                    )
+                    .await;
+                    return;
+                }
+            };
+            if let Some(handle) = self.drivers.lock().unwrap().get_mut(&session_id) {
+                handle.pgid = pid_of(&running.child);
+                handle.app_id = None;
+            }
+            self.record_process(session_id, pid_of(&running.child))
+                .await;
+        }
+    }
+
+    /// Wait for a driver process to exit, recording the application ID it reports along
+    /// the way if it was submitted to a cluster manager
+    async fn wait_for_exit(
+        &self,
+        session_id: u64,
+        command: &DriverCommand,
+        running: &mut Running,
+    ) -> io::Result<ExitStatus> {
+        let mut watching = command.cluster.is_some();
+        loop {
+            tokio::select! {
+                status = running.child.wait() => return status,
+                line = running.lines.recv(), if watching => {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => {
+                            watching = false;
+                            continue;
+                        }
+                    };
+                    let Some(app_id) = command
+                        .cluster
+                        .as_ref()
+                        .and_then(|cluster| cluster.parse_app_id(&line))
+                    else {
+                        continue;
+                    };
+
+                    info!("Driver for session {} is application {}", session_id, app_id);
+                    watching = false;
+                    if let Some(handle) = self.drivers.lock().unwrap().get_mut(&session_id) {
+                        handle.app_id = Some(app_id.clone());
+                    }
+                    if let Err(e) = self
+                        .session_store
+                        .set_session_app_id(session_id, app_id)
+                        .await
+                    {
+                        warn!(
+                            "Failed to record application of session {}: {}",
+                            session_id, e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record the exit of a driver process, returning the session's new state
+    async fn record_exit(
+        &self,
+        session_id: u64,
+        status: io::Result<ExitStatus>,
+    ) -> Option<SessionState> {
+        let current = match self.session_store.get_session_by_id(session_id).await {
+            Ok(Some(session)) => session.state,
+            // The session was already removed
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Failed to look up session {}: {}", session_id, e);
+                return None;
+            }
+        };
+
+        if let Ok(status) = status.as_ref() {
+            if let Err(e) = self
+                .session_store
+                .set_session_exit_code(session_id, status.code())
+                .await
+            {
+                warn!(
+                    "Failed to record exit code of session {}: {}",
+                    session_id, e
+                );
+            }
+        }
+
+        let state = match (current, status) {
+            (SessionState::Stopping, _) => SessionState::Stopped,
+            (SessionState::Ready | SessionState::Idle, Ok(status)) if status.success() => {
+                SessionState::Stopped
+            }
+            (SessionState::Launching, Ok(status)) => SessionState::Failed {
+                reason: format!("Driver exited before becoming ready with {}", status),
+            },
+            (_, Ok(status)) => SessionState::Failed {
+                reason: format!("Driver exited with {}", status),
+            },
+            (_, Err(e)) => SessionState::Failed {
+                reason: format!("Failed to wait on driver: {}", e),
+            },
+        };
+
+        self.set_state(session_id, state.clone()).await;
+        Some(state)
+    }
+
+    /// Stop the driver for a session, if it's running. A driver running on a cluster is
+    /// killed through its cluster manager first. The local process is sent SIGTERM, and
+    /// then SIGKILL if it hasn't exited after the grace period. Returns once the driver
+    /// has exited.
+    pub async fn stop(&self, session_id: u64) -> Result<(), io::Error> {
+        let handle = self.drivers.lock().unwrap().get(&session_id).cloned();
+        let Some(DriverHandle {
+            pgid,
+            mut exited,
+            stop,
+            cluster,
+            app_id,
+        }) = handle
+        else {
+            // Nothing is running for this session
+            self.set_state(session_id, SessionState::Stopped).await;
+            return Ok(());
+        };
+
+        self.set_state(session_id, SessionState::Stopping).await;
+        stop.send_replace(true);
+
+        if let (Some(cluster), Some(app_id)) = (cluster.filter(|c| c.is_remote()), app_id) {
+            info!("Killing application {} for session {}", app_id, session_id);
+            // The local process is still signalled, so a failed kill isn't fatal
+            if let Err(e) = cluster.kill(&app_id).await {
+                warn!("{}", e);
+            }
+        }
+
+        info!("Sending SIGTERM to driver for session {}", session_id);
+        signal_driver(pgid, Signal::SIGTERM)?;
+
+        if tokio::time::timeout(self.stop_grace_period, exited.wait_for(|exited| *exited))
+            .await
+            .is_err()
+        {
+            warn!(
+                "Driver for session {} didn't exit after {:?}, sending SIGKILL",
+                session_id, self.stop_grace_period
+            );
+            signal_driver(pgid, Signal::SIGKILL)?;
+            let _ = exited.wait_for(|exited| *exited).await;
+        }
+        Ok(())
+    }
+
+    /// Whether a driver for the session was started by this supervisor and is still
+    /// being supervised
+    pub fn is_supervised(&self, session_id: u64) -> bool {
+        self.drivers.lock().unwrap().contains_key(&session_id)
+    }
+
+    /// Stop a driver that was started before the proxy restarted, through the process
+    /// group and application recorded for its session. The driver isn't a child of this
+    /// process, so whether it exited is found out by polling its process group. The session
+    /// ends up in `state`.
+    pub async fn stop_recovered(
+        &self,
+        session: &Session,
+        cluster: Option<ClusterApp>,
+        state: SessionState,
+    ) -> Result<(), io::Error> {
+        self.set_state(session.id, SessionState::Stopping).await;
+
+        if let (Some(cluster), Some(app_id)) =
+            (cluster.filter(|c| c.is_remote()), session.app_id.as_ref())
+        {
+            info!("Killing application {} for session {}", app_id, session.id);
+            if let Err(e) = cluster.kill(app_id).await {
+                warn!("{}", e);
+            }
+        }
+
+        // Its process group ID may have been reused by another process since it exited,
+        // which mustn't be signalled
+        match session.driver_process {
+            Some(process) if is_alive(process) => {
+                let pgid = Some(Pid::from_raw(process.pgid));
+                info!(
+                    "Sending SIGTERM to recovered driver for session {}",
+                    session.id
+                );
+                signal_driver(pgid, Signal::SIGTERM)?;
+
+                let deadline = Instant::now() + self.stop_grace_period;
+                while is_alive(process) && Instant::now() < deadline {
+                    tokio::time::sleep(RECOVERED_EXIT_POLL_INTERVAL).await;
+                }
+                if is_alive(process) {
+                    warn!(
+                        "Recovered driver for session {} didn't exit after {:?}, sending SIGKILL",
+                        session.id, self.stop_grace_period
+                    );
+                    signal_driver(pgid, Signal::SIGKILL)?;
+                }
+            }
+            Some(_) => info!("Recovered driver for session {} already exited", session.id),
+            None => (),
+        }
+
+        self.record_process(session.id, None).await;
+        self.set_state(session.id, state).await;
+        Ok(())
+    }
+
+    async fn record_process(&self, session_id: u64, pgid: Option<Pid>) {
+        // A driver whose start time can't be read can't be told apart from a later process,
+        // so isn't recorded
+        let process = pgid.and_then(|pgid| {
+            Some(DriverProcess {
+                pgid: pgid.as_raw(),
+                start_time: start_time(pgid)?,
+            })
+        });
+        if let Err(e) = self
+            .session_store
+            .set_session_driver_process(session_id, process)
+            .await
+        {
+            warn!(
+                "Failed to record driver process of session {}: {}",
+                session_id, e
+            );
+        }
+    }
+
+    async fn set_state(&self, session_id: u64, state: SessionState) -> bool {
+        match self
+            .session_store
+            .set_session_state(session_id, state)
+            .await
+        {
+            Ok(updated) => updated,
+            Err(e) => {
+                warn!("Failed to update state of session {}: {}", session_id, e);
+                false
+            }
+        }
+    }
+}
+
+fn pid_of(child: &Child) -> Option<Pid> {
+    // The pid is only missing once the child has been reaped
+    child.id().map(|pid| Pid::from_raw(pid as i32))
+}
+
+/// When a process started, in clock ticks since boot
+fn start_time(pid: Pid) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name can contain spaces and parentheses, so fields are counted from
+    // the end of it. The start time is the 22nd field, and the state the 3rd.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    fields.nth(19)?.parse().ok()
+}
+
+/// Whether a recovered driver's root process is still the one the driver was started as
+fn is_alive(process: DriverProcess) -> bool {
+    start_time(Pid::from_raw(process.pgid)) == Some(process.start_time)
+}
+
+fn signal_driver(pgid: Option<Pid>, signal: Signal) -> Result<(), io::Error> {
+    let Some(pgid) = pgid else {
+        return Ok(());
+    };
+    match killpg(pgid, signal) {
+        // The process group is already gone
+        Ok(()) | Err(nix::errno::Errno::ESRCH) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::process::CommandExt, process::Command as StdCommand};
+
+    use super::*;
+    use crate::{config::ProxyConfig, store::InMemorySessionStore};
+
+    async fn recovered_session(session_store: &InMemorySessionStore, pgid: Pid) -> Session {
+        let session = session_store
+            .create_session("alice", "token".to_string(), None, None, HashMap::new())
+            .await
+            .unwrap();
+        session_store
+            .set_session_state(session.id, SessionState::Launching)
+            .await
+            .unwrap();
+        session_store
+            .set_session_driver_process(
+                session.id,
+                Some(DriverProcess {
+                    pgid: pgid.as_raw(),
+                    start_time: start_time(pgid).unwrap(),
+                }),
+            )
+            .await
+            .unwrap();
+        session_store
+            .get_session_by_id(session.id)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stops_recovered_drivers_only_if_their_pgid_is_still_theirs() {
+        let session_store = Arc::new(InMemorySessionStore::default());
+        let supervisor = Supervisor::new(
+            session_store.clone(),
+            Arc::new(DriverLogs::from_config(&ProxyConfig::default())),
+            Duration::from_secs(1),
+        );
+        let mut driver = StdCommand::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = Pid::from_raw(driver.id() as i32);
+        let mut session = recovered_session(&session_store, pgid).await;
+
+        // As if the driver exited and its pid was reused
+        let driver_process = session.driver_process.unwrap();
+        session.driver_process = Some(DriverProcess {
+            start_time: driver_process.start_time + 1,
+            ..driver_process
+        });
+        supervisor
+            .stop_recovered(&session, None, SessionState::Stopped)
+            .await
+            .unwrap();
+        assert!(driver.try_wait().unwrap().is_none());
+        let stopped = session_store.get_session_by_id(session.id).await.unwrap();
+        assert_eq!(stopped.unwrap().state, SessionState::Stopped);
+
+        let session = recovered_session(&session_store, pgid).await;
+        supervisor
+            .stop_recovered(&session, None, SessionState::Stopped)
+            .await
+            .unwrap();
+        assert!(driver.wait().unwrap().code().is_none());
+        let stopped = session_store.get_session_by_id(session.id).await.unwrap();
+        assert_eq!(stopped.unwrap().state, SessionState::Stopped);
+    }
+}
@@ -0,0 +1,461 @@
+/// Module for the HTTP/2 connections from the proxy to session drivers
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::body::Body;
+use http::{header::AUTHORIZATION, HeaderValue};
+use http_body_util::BodyExt;
+use hyper::{client::conn::http2::SendRequest, Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{info, warn};
+use tokio::net::TcpStream;
+
+use crate::{
+    grpc::{self, Code},
+    idle::IdleSessions,
+    store,
+};
+
+// Attempts made to (re)connect to a driver before failing the calls waiting on it
+const CONNECT_ATTEMPTS: u32 = 4;
+// Wait before the first reconnect attempt, doubled after each failure
+const CONNECT_BACKOFF: Duration = Duration::from_millis(250);
+// Longest a single attempt to connect to a driver may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Route {
+    session_id: u64,
+    // Unix timestamp in seconds the token expires at, if it does
+    expires_at: Option<u64>,
+}
+
+#[derive(Default)]
+struct Pooled {
+    // Connection to each session's driver
+    connections: HashMap<u64, Arc<Upstream>>,
+    // Session each token was last routed to
+    tokens: HashMap<String, Route>,
+}
+
+/// One shared connection per session driver, multiplexing the calls from every client
+/// connection for that session
+pub struct UpstreamPool {
+    idle_sessions: Arc<IdleSessions>,
+    pooled: Mutex<Pooled>,
+}
+
+impl UpstreamPool {
+    pub fn new(idle_sessions: Arc<IdleSessions>) -> Self {
+        Self {
+            idle_sessions,
+            pooled: Mutex::new(Pooled::default()),
+        }
+    }
+
+    /// The usable connection a token was last routed to, if any. Expired tokens are
+    /// looked up again so the call is rejected.
+    pub fn find(&self, token: &str) -> Option<Arc<Upstream>> {
+        let pooled = self.pooled.lock().unwrap();
+        pooled
+            .tokens
+            .get(token)
+            .filter(|route| {
+                route
+                    .expires_at
+                    .is_none_or(|expires_at| store::now() < expires_at)
+            })
+            .and_then(|route| pooled.connections.get(&route.session_id))
+            .filter(|upstream| !upstream.is_unreachable())
+            .cloned()
+    }
+
+    /// Get the connection to a session's driver, adding it if there isn't one. The driver
+    /// is connected to when the first call is sent.
+    pub fn connect(
+        &self,
+        token: &str,
+        session_id: u64,
+        addr: String,
+        authorization: HeaderValue,
+        expires_at: Option<u64>,
+    ) -> Arc<Upstream> {
+        let mut pooled = self.pooled.lock().unwrap();
+        pooled.tokens.insert(
+            token.to_string(),
+            Route {
+                session_id,
+                expires_at,
+            },
+        );
+
+        if let Some(upstream) = pooled.connections.get(&session_id).filter(|upstream| {
+            !upstream.is_unreachable()
+                && upstream.addr == addr
+                && upstream.authorization == authorization
+        }) {
+            return upstream.clone();
+        }
+
+        let upstream = Arc::new(Upstream {
+            session_id,
+            addr,
+            authorization,
+            idle_sessions: self.idle_sessions.clone(),
+            sender: tokio::sync::Mutex::new(None),
+            unreachable: AtomicBool::new(false),
+        });
+        pooled.connections.insert(session_id, upstream.clone());
+        upstream
+    }
+
+    /// Drop the connection for a session. Calls already sent on it are left to finish.
+    pub fn evict(&self, session_id: u64) {
+        let mut pooled = self.pooled.lock().unwrap();
+        pooled.connections.remove(&session_id);
+        pooled
+            .tokens
+            .retain(|_, route| route.session_id != session_id);
+    }
+
+    /// Stop routing a session's old tokens after its token was rotated. The connection
+    /// to the driver is kept for the new token.
+    pub fn revoke(&self, session_id: u64) {
+        self.pooled
+            .lock()
+            .unwrap()
+            .tokens
+            .retain(|_, route| route.session_id != session_id);
+    }
+}
+
+/// An HTTP/2 connection to a session's driver. Calls are sent concurrently as separate
+/// streams, and the connection is reopened when the driver closes it.
+pub struct Upstream {
+    session_id: u64,
+    addr: String,
+    // Bearer token the driver was launched with, sent along with every call whatever
+    // the call was routed by
+    authorization: HeaderValue,
+    idle_sessions: Arc<IdleSessions>,
+    // The current connection, locked while reconnecting so only one call reconnects
+    sender: tokio::sync::Mutex<Option<SendRequest<Body>>>,
+    // Set once reconnecting gave up, so the session is looked up again
+    unreachable: AtomicBool,
+}
+
+impl Upstream {
+    fn is_unreachable(&self) -> bool {
+        self.unreachable.load(Ordering::Relaxed)
+    }
+
+    /// Proxy a call to the driver
+    pub async fn send(&self, mut req: Request<Body>) -> Response<Body> {
+        let call = self.idle_sessions.start_call(self.session_id);
+
+        let uri = format!(
+            "http://{}{}",
+            self.addr,
+            req.uri()
+                .path_and_query()
+                .map(|x| x.as_str())
+                .unwrap_or("/")
+        );
+        *req.uri_mut() = match uri.parse() {
+            Ok(uri) => uri,
+            Err(err) => {
+                warn!("Invalid upstream URI {}: {}", uri, err);
+                return grpc::error_response(
+                    Code::Internal,
+                    "Unable to build the upstream request",
+                );
+            }
+        };
+
+        req.headers_mut()
+            .insert(AUTHORIZATION, self.authorization.clone());
+
+        info!("Proxying request {:?}", req.uri().path_and_query());
+
+        // A call the connection turned away before sending, like after a GOAWAY, is
+        // retried once on a new connection
+        let mut retried = false;
+        loop {
+            let Some(mut sender) = self.sender().await else {
+                return grpc::error_response(
+                    Code::Unavailable,
+                    "Unable to connect to the Spark driver",
+                );
+            };
+
+            match sender.try_send_request(req).await {
+                Ok(response) => {
+                    // The call is in flight until its response body is done or dropped
+                    return response.map(|body| {
+                        Body::new(body.map_frame(move |frame| {
+                            let _ = &call;
+                            frame
+                        }))
+                    });
+                }
+                Err(mut err) => match err.take_message() {
+                    Some(unsent) if !retried => {
+                        warn!(
+                            "Connection to the driver for session {} was lost, reconnecting",
+                            self.session_id
+                        );
+                        retried = true;
+                        req = unsent;
+                    }
+                    _ => {
+                        let err = err.into_error();
+                        warn!(
+                            "Request to the driver for session {} failed: {}",
+                            self.session_id, err
+                        );
+                        return grpc::error_response(
+                            Code::Unavailable,
+                            &format!("Request to the Spark driver failed: {}", err),
+                        );
+                    }
+                },
+            }
+        }
+    }
+
+    /// A handle to the current connection, connecting first if it was closed
+    async fn sender(&self) -> Option<SendRequest<Body>> {
+        let mut sender = self.sender.lock().await;
+        if let Some(sender) = sender.as_ref().filter(|sender| !sender.is_closed()) {
+            return Some(sender.clone());
+        }
+        // Calls that queued up behind a failed reconnect fail right away
+        if self.is_unreachable() {
+            return None;
+        }
+
+        *sender = self.connect_with_backoff().await;
+        if sender.is_none() {
+            self.unreachable.store(true, Ordering::Relaxed);
+        }
+        sender.clone()
+    }
+
+    async fn connect_with_backoff(&self) -> Option<SendRequest<Body>> {
+        let mut backoff = CONNECT_BACKOFF;
+        for attempt in 1..=CONNECT_ATTEMPTS {
+            match self.connect().await {
+                Ok(sender) => return Some(sender),
+                Err(err) => warn!(
+                    "Failed to connect to the driver for session {} at {} ({}/{}): {}",
+                    self.session_id, self.addr, attempt, CONNECT_ATTEMPTS, err
+                ),
+            }
+            if attempt < CONNECT_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        None
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>, Box<dyn std::error::Error + Send + Sync>> {
+        // A driver host that drops packets would otherwise hold up calls until the OS
+        // gives up on the connection
+        let (sender, conn) = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let client_stream = TcpStream::connect(&self.addr).await?;
+            let io = TokioIo::new(client_stream);
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), io).await?,
+            )
+        })
+        .await
+        .map_err(|_| format!("Timed out after {:?}", CONNECT_TIMEOUT))??;
+        let session_id = self.session_id;
+        tokio::task::spawn(async move {
+            // A GOAWAY or reset ends the connection here, and the next call reconnects
+            if let Err(err) = conn.await {
+                warn!(
+                    "Connection to the driver for session {} failed: {:?}",
+                    session_id, err
+                );
+            }
+        });
+        Ok(sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        config::ProxyConfig,
+        fake_driver::{Behavior, FakeDriver},
+    };
+
+    fn pool() -> UpstreamPool {
+        UpstreamPool::new(Arc::new(IdleSessions::from_config(&ProxyConfig::default())))
+    }
+
+    fn connect(pool: &UpstreamPool, addr: &str) -> Arc<Upstream> {
+        pool.connect(
+            "token",
+            1,
+            addr.to_string(),
+            HeaderValue::from_static("Bearer driver-token"),
+            None,
+        )
+    }
+
+    fn call() -> Request<Body> {
+        Request::builder()
+            .uri("/spark.connect.SparkConnectService/ExecutePlan")
+            .header(AUTHORIZATION, "Bearer token")
+            .body(Body::from("plan"))
+            .unwrap()
+    }
+
+    fn grpc_status(response: &Response<Body>) -> (&str, &str) {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .map(|value: &HeaderValue| value.to_str().unwrap())
+                .unwrap_or_default()
+        };
+        (header("grpc-status"), header("grpc-message"))
+    }
+
+    #[tokio::test]
+    async fn sends_calls_with_the_driver_token() {
+        let driver = FakeDriver::start(Behavior::Respond(Duration::ZERO)).await;
+        let pool = pool();
+
+        let response = connect(&pool, &driver.addr).send(call()).await;
+        assert_eq!(grpc_status(&response).0, "0");
+        assert_eq!(response.headers()["x-authorization"], "Bearer driver-token");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "plan");
+
+        // Later calls share the connection
+        let response = pool.find("token").unwrap().send(call()).await;
+        assert_eq!(grpc_status(&response).0, "0");
+        assert_eq!(driver.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_goaway() {
+        let driver = FakeDriver::start(Behavior::GoAway).await;
+        let upstream = connect(&pool(), &driver.addr);
+
+        for _ in 0..3 {
+            let response = upstream.send(call()).await;
+            assert_eq!(grpc_status(&response).0, "0");
+            response.into_body().collect().await.unwrap();
+        }
+        assert_eq!(driver.connections(), 3);
+    }
+
+    #[tokio::test]
+    async fn fails_calls_on_reset() {
+        let driver = FakeDriver::start(Behavior::Reset).await;
+        let pool = pool();
+
+        let response = connect(&pool, &driver.addr).send(call()).await;
+        let (status, message) = grpc_status(&response);
+        assert_eq!(status, "14");
+        assert!(message.starts_with("Request to the Spark driver failed"));
+        // The driver may still be there, so the connection is kept for the next call
+        assert!(pool.find("token").is_some());
+    }
+
+    #[tokio::test]
+    async fn fails_calls_to_unreachable_drivers() {
+        // Nothing listens on a port that was just released
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let pool = pool();
+
+        let response = connect(&pool, &addr).send(call()).await;
+        assert_eq!(
+            grpc_status(&response),
+            ("14", "Unable to connect to the Spark driver")
+        );
+        // The session is looked up again on the next call
+        assert!(pool.find("token").is_none());
+    }
+
+    #[tokio::test]
+    async fn forgets_expired_and_revoked_tokens() {
+        let pool = pool();
+        pool.connect(
+            "expired",
+            1,
+            "127.0.0.1:1".to_string(),
+            HeaderValue::from_static("Bearer driver-token"),
+            Some(store::now() - 1),
+        );
+        assert!(pool.find("expired").is_none());
+
+        connect(&pool, "127.0.0.1:1");
+        assert!(pool.find("token").is_some());
+        pool.revoke(1);
+        assert!(pool.find("token").is_none());
+    }
+
+    /// Send `calls` calls at once to a driver that takes `delay` to answer each one,
+    /// returning how long it took for all of them to finish
+    async fn send_concurrently(calls: usize, delay: Duration) -> Duration {
+        let driver = FakeDriver::start(Behavior::Respond(delay)).await;
+        let upstream = connect(&pool(), &driver.addr);
+
+        let start = std::time::Instant::now();
+        let sent = (0..calls).map(|_| {
+            let upstream = upstream.clone();
+            tokio::task::spawn(async move {
+                let response = upstream.send(call()).await;
+                assert_eq!(grpc_status(&response).0, "0");
+                response.into_body().collect().await.unwrap();
+            })
+        });
+        for call in futures_util::future::join_all(sent).await {
+            call.unwrap();
+        }
+        assert_eq!(driver.connections(), 1);
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn sends_concurrent_calls_in_parallel() {
+        let elapsed = send_concurrently(10, Duration::from_millis(500)).await;
+        // One after the other they'd take five seconds
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    /// Throughput of many concurrent calls through one session. Run with
+    /// `cargo test --release concurrent_call_throughput -- --ignored --nocapture`. The
+    /// fake driver allows 200 concurrent streams, so calls past that queue up.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn concurrent_call_throughput() {
+        let delay = Duration::from_secs(1);
+        for calls in [10, 100, 1000] {
+            let elapsed = send_concurrently(calls, delay).await;
+            println!(
+                "{} calls held {:?} each: {:.2?}, {:.0} calls/s",
+                calls,
+                delay,
+                elapsed,
+                calls as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
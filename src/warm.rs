@@ -0,0 +1,328 @@
+/// Module for keeping drivers started ahead of the sessions that will use them
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::{
+    config::ProxyConfig,
+    idle::IdleSessions,
+    launcher::Launcher,
+    store::{Session, SessionState, SessionStore, StoreResult},
+};
+
+// User that warm sessions belong to until they're handed out. HTTP Basic auth doesn't
+// allow a colon in usernames, and other authenticators are refused it
+pub const WARM_USERNAME: &str = "spark-connect-proxy:warm";
+// How often the pool is checked for drivers that exited, besides after each hand out
+const REFILL_INTERVAL: Duration = Duration::from_secs(10);
+
+struct WarmVersion {
+    size: usize,
+    idle_timeout: Option<u64>,
+}
+
+/// Keeps a number of drivers per Spark version started with just the version's configs,
+/// and hands them to new sessions whose configs are the same, so those sessions are ready
+/// right away. Warm drivers aren't counted against quotas until they're handed out.
+pub struct WarmPool {
+    versions: HashMap<String, WarmVersion>,
+    session_store: Arc<dyn SessionStore>,
+    launcher: Arc<dyn Launcher>,
+    idle_sessions: Arc<IdleSessions>,
+    // Version of each warm session not handed out yet by session ID
+    sessions: Mutex<HashMap<u64, String>>,
+    refill: Notify,
+}
+
+impl WarmPool {
+    pub fn from_config(
+        config: &ProxyConfig,
+        session_store: Arc<dyn SessionStore>,
+        launcher: Arc<dyn Launcher>,
+        idle_sessions: Arc<IdleSessions>,
+    ) -> Self {
+        let versions = config
+            .spark_versions
+            .iter()
+            .filter_map(|version| {
+                let size = version.warm_pool_size.filter(|size| *size > 0)?;
+                assert!(
+                    version.impersonation.is_none(),
+                    "Version {} can't keep warm drivers, they'd run as the wrong user",
+                    version.name
+                );
+                Some((
+                    version.name.clone(),
+                    WarmVersion {
+                        size,
+                        idle_timeout: version.idle_timeout,
+                    },
+                ))
+            })
+            .collect();
+
+        Self {
+            versions,
+            session_store,
+            launcher,
+            idle_sessions,
+            sessions: Mutex::new(HashMap::new()),
+            refill: Notify::new(),
+        }
+    }
+
+    /// Start the warm drivers, and start new ones as drivers are handed out or exit
+    pub fn spawn_refill(self: &Arc<Self>) {
+        if self.versions.is_empty() {
+            return;
+        }
+        let pool = self.clone();
+        tokio::task::spawn(async move {
+            pool.remove_leftovers().await;
+            let mut interval = tokio::time::interval(REFILL_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => (),
+                    _ = pool.refill.notified() => (),
+                }
+                pool.refill().await;
+            }
+        });
+    }
+
+    /// Stop and remove warm sessions persisted before a restart, which aren't supervised
+    /// anymore
+    async fn remove_leftovers(&self) {
+        let sessions = match self.session_store.list_sessions(WARM_USERNAME).await {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                warn!("Failed to look up leftover warm sessions: {}", e);
+                return;
+            }
+        };
+        for session in sessions {
+            warn!(
+                "Removing warm session {} left over from before a restart",
+                session.id
+            );
+            // Their drivers outlive the proxy, and can't be found again once the session is
+            if let Err(e) = self.launcher.stop(session.id).await {
+                warn!("Failed to stop warm session {}: {}", session.id, e);
+                continue;
+            }
+            if let Err(e) = self
+                .session_store
+                .delete_session(WARM_USERNAME, session.id)
+                .await
+            {
+                warn!("Failed to remove warm session {}: {}", session.id, e);
+            }
+        }
+    }
+
+    async fn refill(&self) {
+        // Drop the warm sessions whose drivers exited, they're replaced below
+        let ids: Vec<u64> = self.sessions.lock().unwrap().keys().copied().collect();
+        for session_id in ids {
+            match self
+                .session_store
+                .get_session(WARM_USERNAME, session_id)
+                .await
+            {
+                Ok(Some(session))
+                    if matches!(
+                        session.state,
+                        SessionState::Stopped | SessionState::Failed { .. }
+                    ) =>
+                {
+                    warn!("Warm session {} is {}", session_id, session.state.name());
+                    self.remove(session_id).await;
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    self.sessions.lock().unwrap().remove(&session_id);
+                }
+                Err(e) => warn!("Failed to look up warm session {}: {}", session_id, e),
+            }
+        }
+
+        for (version_name, version) in self.versions.iter() {
+            let warm = self
+                .sessions
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|version| *version == version_name)
+                .count();
+            for _ in warm..version.size {
+                if let Err(e) = self.start(version_name).await {
+                    warn!(
+                        "Failed to start a warm driver for version {}: {}",
+                        version_name, e
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn start(&self, version_name: &str) -> StoreResult<()> {
+        // Replaced when the session is handed out, the driver has a token of its own
+        let token = Uuid::new_v4().to_string();
+        let session = self
+            .session_store
+            .create_session(
+                WARM_USERNAME,
+                token,
+                None,
+                Some(version_name.to_string()),
+                HashMap::new(),
+            )
+            .await?;
+        info!(
+            "Starting warm session {} for version {}",
+            session.id, version_name
+        );
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.id, version_name.to_string());
+
+        let launched = self
+            .launcher
+            .launch(
+                session.id,
+                Some(version_name),
+                WARM_USERNAME.to_string(),
+                HashMap::new(),
+            )
+            .await;
+        // Warm drivers wait for a user however long it takes
+        self.idle_sessions.forget(session.id);
+        Ok(launched?)
+    }
+
+    async fn remove(&self, session_id: u64) {
+        self.sessions.lock().unwrap().remove(&session_id);
+        if let Err(e) = self
+            .session_store
+            .delete_session(WARM_USERNAME, session_id)
+            .await
+        {
+            warn!("Failed to remove warm session {}: {}", session_id, e);
+        }
+        self.launcher.remove_logs(session_id).await;
+    }
+
+    /// Hand a ready warm driver over to a user's new session, if the version keeps any and
+    /// the configs the session would run with are the ones the driver was started with.
+    /// The session takes over the warm session's ID, and keeps `token` as its own.
+    pub async fn claim(
+        &self,
+        version_name: &str,
+        username: &str,
+        token: String,
+        token_expires_at: Option<u64>,
+        user_config: HashMap<String, String>,
+        effective_config: &HashMap<String, String>,
+    ) -> StoreResult<Option<Session>> {
+        let Some(version) = self.versions.get(version_name) else {
+            return Ok(None);
+        };
+        match self
+            .launcher
+            .effective_config(Some(version_name), HashMap::new())
+        {
+            Ok((_, warm_config)) if warm_config == *effective_config => (),
+            _ => return Ok(None),
+        }
+
+        let candidates: Vec<u64> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, version)| *version == version_name)
+            .map(|(id, _)| *id)
+            .collect();
+        for session_id in candidates {
+            let ready = self
+                .session_store
+                .get_session(WARM_USERNAME, session_id)
+                .await?
+                .is_some_and(|session| session.state == SessionState::Ready);
+            // Another session may have claimed it in the meantime
+            if !ready
+                || !self
+                    .session_store
+                    .assign_session(
+                        session_id,
+                        WARM_USERNAME,
+                        username,
+                        token.clone(),
+                        token_expires_at,
+                        user_config.clone(),
+                    )
+                    .await?
+            {
+                continue;
+            }
+
+            info!("Handed warm session {} to {}", session_id, username);
+            self.sessions.lock().unwrap().remove(&session_id);
+            self.refill.notify_one();
+            self.idle_sessions
+                .track(session_id, username, version.idle_timeout);
+            return self.session_store.get_session(username, session_id).await;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_launcher::FakeLauncher, store::InMemorySessionStore};
+
+    #[tokio::test]
+    async fn stops_leftover_warm_drivers_before_removing_them() {
+        let config = ProxyConfig::default();
+        let session_store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::default());
+        let launcher = Arc::new(FakeLauncher::new(session_store.clone(), Duration::ZERO));
+        let pool = WarmPool::from_config(
+            &config,
+            session_store.clone(),
+            launcher.clone(),
+            Arc::new(IdleSessions::from_config(&config)),
+        );
+
+        let session = session_store
+            .create_session(
+                WARM_USERNAME,
+                "token".to_string(),
+                None,
+                None,
+                HashMap::new(),
+            )
+            .await
+            .unwrap();
+        session_store
+            .set_session_state(session.id, SessionState::Launching)
+            .await
+            .unwrap();
+
+        pool.remove_leftovers().await;
+        assert_eq!(*launcher.stopped.lock().unwrap(), vec![session.id]);
+        assert!(session_store
+            .list_sessions(WARM_USERNAME)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}